@@ -1,78 +1,358 @@
 use std::boxed::Box;
-use std::env::Args as EnvArgs;
+use std::env::var as env_var;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::mem::zeroed;
+use std::os::unix::io::AsRawFd;
 use std::io::Write;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::io::stdin;
-use std::io::stdout;
 
 use libc::isatty;
+use libc::tcgetattr;
+use libc::tcsetattr;
+use libc::termios;
+use libc::ECHO;
+use libc::ECHONL;
+use libc::TCSANOW;
 use libc::STDIN_FILENO;
 
+use ed25519_dalek::SigningKey;
+use ed25519_dalek::VerifyingKey;
+use x25519_dalek::PublicKey;
+use x25519_dalek::StaticSecret;
+
+use super::ecc::EccParams;
+use super::ecc::MAX_PARITY;
+use super::ecc::MIN_PARITY;
 use super::error::Error;
+use super::keys::parse_ed25519_key_file;
+use super::keys::parse_ed25519_public;
+use super::keys::parse_x25519_key_file;
+use super::keys::parse_x25519_public;
+use super::secret::EmbedOptions;
+use super::secret::MAX_BITS_PER_CHANNEL;
 
 enum Reading {
     Message,
+    Depth,
     Output,
-    Input
+    Input,
+    File,
+    MessageFile,
+    MessageEnv,
+    PasswordEnv,
+    PasswordFile,
+    Recipient,
+    Identity,
+    SignWith,
+    VerifyWith,
+    KeyType,
+    Ecc,
+    EccInterleave,
+    Key,
+    BitsPerChannel,
+    Channels,
+    Adaptive,
+    MaxLength,
 }
 
 pub struct Args<'a> {
     pub command: Option<String>,
     pub input: Box<Read + 'a>,
-    pub output: Box<Write + 'a>,
     pub message: String,
+    pub file: Option<String>,
+    pub output_path: Option<String>,
+    pub uncompressed: bool,
+    pub depth: u8,
+    pub padding: bool,
+    pub max_length: Option<u32>,
+    pub legacy: bool,
+    pub restore_name: bool,
+    pub password: Option<String>,
+    pub recipients: Vec<PublicKey>,
+    pub identity: Option<StaticSecret>,
+    pub signing_key: Option<SigningKey>,
+    pub signer: Option<VerifyingKey>,
+    /// x25519 or ed25519
+    pub key_type: String,
+    pub ecc: Option<EccParams>,
+    /// pixel order key, the seed is derived from it for each image
+    pub key: Option<String>,
+    /// rgba
+    pub bits_per_channel: [u8; 4],
+    /// rgba channels chosen with --channels
+    pub channels: [bool; 4],
+    pub translucent_only: bool,
+    pub lsb_matching: bool,
+    pub matrix_embedding: bool,
+    pub adaptive: Option<u8>,
 }
 
 impl<'a> Args<'a> {
-    pub fn from_env_args(env_args: EnvArgs) -> Result<Args<'a>, Error> {
+    pub fn from_env_args<I: IntoIterator<Item = String>>(env_args: I) -> Result<Args<'a>, Error> {
         let mut args = Args {
             command: None,
             input: Box::new(stdin()),
-            output: Box::new(stdout()),
             message: String::from(""),
+            file: None,
+            output_path: None,
+            uncompressed: false,
+            depth: 24,
+            padding: false,
+            max_length: None,
+            legacy: false,
+            restore_name: false,
+            password: None,
+            recipients: Vec::new(),
+            identity: None,
+            signing_key: None,
+            signer: None,
+            key_type: String::from("x25519"),
+            ecc: None,
+            key: None,
+            bits_per_channel: [1; 4],
+            channels: [true; 4],
+            translucent_only: false,
+            lsb_matching: false,
+            matrix_embedding: false,
+            adaptive: None,
         };
 
         let mut reading: Option<Reading> = None;
         let mut input_from_stdin = true;
+        // where the message comes from, at most one of them
+        let mut message_sources = 0;
+        let mut message_stdin = false;
+        let mut password_sources = 0;
+        let mut password_prompt = false;
+        let mut ecc_depth = None;
 
         for arg in env_args {
             match reading {
                 Some(stuff) => {
                     match stuff {
                         Reading::Message => args.parse_message(&arg),
+                        Reading::Depth => try!(args.parse_depth(&arg)),
+                        Reading::MessageFile => try!(args.parse_message_file(&arg)),
+                        Reading::MessageEnv => try!(args.parse_message_env(&arg)),
+                        Reading::PasswordEnv => args.password = Some(try!(read_env(&arg))),
+                        Reading::PasswordFile => try!(args.parse_password_file(&arg)),
+                        Reading::Recipient => args.recipients.push(try!(parse_x25519_public(&arg))),
+                        Reading::Identity => try!(args.parse_identity(&arg)),
+                        Reading::SignWith => try!(args.parse_signing_key(&arg)),
+                        Reading::VerifyWith => args.signer = Some(try!(parse_ed25519_public(&arg))),
+                        Reading::KeyType => try!(args.parse_key_type(&arg)),
+                        Reading::Ecc => try!(args.parse_ecc(&arg)),
+                        Reading::EccInterleave => ecc_depth = Some(try!(parse_ecc_depth(&arg))),
+                        Reading::Key => try!(args.parse_key(&arg)),
+                        Reading::BitsPerChannel => try!(args.parse_bits_per_channel(&arg)),
+                        Reading::Channels => try!(args.parse_channels(&arg)),
+                        Reading::Adaptive => try!(args.parse_adaptive(&arg)),
                         Reading::Input => {
                             try!(args.parse_input(&arg));
                             input_from_stdin = false;
                         },
-                        Reading::Output => try!(args.parse_output(&arg)),
+                        Reading::Output => args.output_path = Some(arg.clone()),
+                        Reading::File => args.file = Some(arg.clone()),
+                        Reading::MaxLength => try!(args.parse_max_length(&arg)),
                     }
                     reading = None;
                 },
                 None => {
                     match arg.as_str() {
-                        "--message" | "-m" => reading = Some(Reading::Message),
+                        "--message" | "-m" => {
+                            reading = Some(Reading::Message);
+                            message_sources += 1;
+                        },
+                        "--message-file" => {
+                            reading = Some(Reading::MessageFile);
+                            message_sources += 1;
+                        },
+                        "--message-env" => {
+                            reading = Some(Reading::MessageEnv);
+                            message_sources += 1;
+                        },
+                        "--message-stdin" => {
+                            message_stdin = true;
+                            message_sources += 1;
+                        },
                         "--output" | "-o" => reading = Some(Reading::Output),
                         "--input" | "-i" => reading = Some(Reading::Input),
+                        "--password" => {
+                            password_prompt = true;
+                            password_sources += 1;
+                        },
+                        "--password-env" => {
+                            reading = Some(Reading::PasswordEnv);
+                            password_sources += 1;
+                        },
+                        "--password-file" => {
+                            reading = Some(Reading::PasswordFile);
+                            password_sources += 1;
+                        },
+                        "--recipient" => reading = Some(Reading::Recipient),
+                        "--identity" => reading = Some(Reading::Identity),
+                        "--sign-with" => reading = Some(Reading::SignWith),
+                        "--verify-with" => reading = Some(Reading::VerifyWith),
+                        "--key-type" => reading = Some(Reading::KeyType),
+                        "--ecc" => reading = Some(Reading::Ecc),
+                        "--ecc-interleave" => reading = Some(Reading::EccInterleave),
+                        "--key" => reading = Some(Reading::Key),
+                        "--bits-per-channel" => reading = Some(Reading::BitsPerChannel),
+                        "--channels" => reading = Some(Reading::Channels),
+                        "--translucent-only" => args.translucent_only = true,
+                        "--lsb-matching" => args.lsb_matching = true,
+                        "--matrix-embedding" => args.matrix_embedding = true,
+                        "--adaptive" => reading = Some(Reading::Adaptive),
+                        "--file" | "-f" => {
+                            reading = Some(Reading::File);
+                            message_sources += 1;
+                        },
+                        "--uncompressed" => args.uncompressed = true,
+                        "--depth" => reading = Some(Reading::Depth),
+                        "--padding" => args.padding = true,
+                        "--max-length" => reading = Some(Reading::MaxLength),
+                        "--legacy" => args.legacy = true,
+                        "--restore-name" => args.restore_name = true,
                         command @ "show" |
-                        command @ "hide" => args.command = Some(String::from(command)),
+                        command @ "convert" |
+                        command @ "hide" |
+                        command @ "capacity" |
+                        command @ "keygen" => args.command = Some(String::from(command)),
                         _ => {},
                     }
                 }
             }
         }
 
-        if args.command.is_some() && input_from_stdin {
+        if message_sources > 1 {
+            return Err(Error::new("Use only one of --message, --message-file, --message-env, --message-stdin and --file"))
+        }
+        if password_sources > 1 {
+            return Err(Error::new("Use only one of --password, --password-env and --password-file"))
+        }
+        if password_sources > 0 && !args.recipients.is_empty() {
+            return Err(Error::new("Use either a password or recipients"))
+        }
+        if let Some(depth) = ecc_depth {
+            match args.ecc {
+                Some(ref mut ecc) => ecc.depth = depth,
+                None => return Err(Error::new("--ecc-interleave needs --ecc")),
+            }
+        }
+        if args.lsb_matching && args.adaptive.is_some() {
+            return Err(Error::new("Use either --lsb-matching or --adaptive, \
+                                   adding or subtracting 1 changes the bits texture is measured on"))
+        }
+        if password_prompt {
+            let confirm = args.command.as_ref().map_or(false, |command| command == "hide");
+            args.password = Some(try!(prompt_password(confirm)));
+        }
+        if let Some(ref password) = args.password {
+            if password.is_empty() {
+                return Err(Error::new("Empty password"))
+            }
+        }
+
+        if message_stdin {
+            if input_from_stdin {
+                return Err(Error::new("The image must be given with -i when the message is read from stdin"))
+            }
+            try!(args.parse_message_stdin());
+        }
+
+        let reads_image = args.command.as_ref().map_or(false, |command| command != "keygen");
+        if reads_image && input_from_stdin {
             try!(assert_stdin_is_piped());
         }
 
         Ok(args)
     }
 
+    /// Options on how secret bits are placed in pixels
+    pub fn embed_options(&self) -> EmbedOptions {
+        EmbedOptions {
+            padding: self.padding,
+            seed: None,
+            bits_per_channel: self.selected_bits_per_channel(),
+            translucent_only: self.translucent_only,
+            matching_seed: None,
+            adaptive: self.adaptive,
+        }
+    }
+
+    /// Bits per channel, 0 for channels left out by --channels
+    pub fn selected_bits_per_channel(&self) -> [u8; 4] {
+        let mut bits = self.bits_per_channel;
+        for (bits, selected) in bits.iter_mut().zip(&self.channels) {
+            if !selected {
+                *bits = 0;
+            }
+        }
+        bits
+    }
+
     fn parse_message(&mut self, arg: &String) {
         self.message = String::from(arg.as_str());
     }
 
+    fn parse_depth(&mut self, arg: &String) -> Result<(), Error> {
+        match arg.as_str() {
+            "24" | "32" => {
+                self.depth = if arg == "24" { 24 } else { 32 };
+                Ok(())
+            },
+            _ => Err(Error::new("Invalid pixel depth, use 24 or 32")),
+        }
+    }
+
+    fn parse_message_file(&mut self, arg: &String) -> Result<(), Error> {
+        let mut file = try!(File::open(&arg));
+        try!(read_message(&mut file, &mut self.message));
+        Ok(())
+    }
+
+    fn parse_message_env(&mut self, arg: &String) -> Result<(), Error> {
+        self.message = try!(read_env(arg));
+        Ok(())
+    }
+
+    /// The first line of the file is the password
+    fn parse_password_file(&mut self, arg: &String) -> Result<(), Error> {
+        let mut file = try!(File::open(&arg));
+        let mut password = String::new();
+        try!(read_message(&mut file, &mut password));
+        self.password = Some(String::from(password.lines().next().unwrap_or("")));
+        Ok(())
+    }
+
+    fn parse_message_stdin(&mut self) -> Result<(), Error> {
+        try!(read_message(&mut stdin(), &mut self.message));
+        Ok(())
+    }
+
+    fn parse_identity(&mut self, arg: &String) -> Result<(), Error> {
+        self.identity = Some(try!(parse_x25519_key_file(&try!(read_key_file(arg)))));
+        Ok(())
+    }
+
+    fn parse_signing_key(&mut self, arg: &String) -> Result<(), Error> {
+        self.signing_key = Some(try!(parse_ed25519_key_file(&try!(read_key_file(arg)))));
+        Ok(())
+    }
+
+    fn parse_key_type(&mut self, arg: &String) -> Result<(), Error> {
+        match arg.as_str() {
+            "x25519" | "ed25519" => {
+                self.key_type = arg.clone();
+                Ok(())
+            },
+            _ => Err(Error::new("Invalid key type, use x25519 or ed25519")),
+        }
+    }
+
     fn parse_input(&mut self, arg: &String) -> Result<(), Error> {
         let file = try!(File::open(&arg));
 
@@ -80,13 +360,171 @@ impl<'a> Args<'a> {
         Ok(())
     }
 
-    fn parse_output(&mut self, arg: &String) -> Result<(), Error> {
-        let file = try!(File::create(&arg));
+    fn parse_ecc(&mut self, arg: &String) -> Result<(), Error> {
+        match arg.parse() {
+            Ok(parity) if parity >= MIN_PARITY && parity <= MAX_PARITY => {
+                self.ecc = Some(EccParams {
+                    parity: parity,
+                    depth: 0,
+                });
+                Ok(())
+            },
+            _ => Err(Error::new(&format!("Invalid error correction parity, use {} to {} bytes",
+                                         MIN_PARITY, MAX_PARITY))),
+        }
+    }
+
+    /// A number for all channels, or some of them: `r=2,g=2,b=3`
+    fn parse_bits_per_channel(&mut self, arg: &String) -> Result<(), Error> {
+        for item in arg.split(',') {
+            let (channels, bits) = match item.find('=') {
+                Some(position) => {
+                    let channel = match &item[..position] {
+                        "r" => 0,
+                        "g" => 1,
+                        "b" => 2,
+                        "a" => 3,
+                        _ => return Err(Error::new("Invalid channel, use r, g, b or a")),
+                    };
+                    (channel..channel + 1, &item[position + 1..])
+                },
+                None => (0..4, item),
+            };
+            let bits = match bits.parse() {
+                Ok(bits) if bits >= 1 && bits <= MAX_BITS_PER_CHANNEL => bits,
+                _ => return Err(Error::new(&format!("Invalid bits per channel, use 1 to {}", MAX_BITS_PER_CHANNEL))),
+            };
+            for channel in channels {
+                self.bits_per_channel[channel] = bits;
+            }
+        }
+        Ok(())
+    }
 
-        self.output = Box::new(file);
+    /// Some of `rgba`, in any order
+    fn parse_channels(&mut self, arg: &String) -> Result<(), Error> {
+        let mut channels = [false; 4];
+        for name in arg.chars() {
+            match "rgba".find(name) {
+                Some(channel) => channels[channel] = true,
+                None => return Err(Error::new("Invalid channels, use some of r, g, b and a")),
+            }
+        }
+        if !channels.iter().any(|selected| *selected) {
+            return Err(Error::new("No channel selected"))
+        }
+        self.channels = channels;
         Ok(())
     }
 
+    fn parse_key(&mut self, arg: &String) -> Result<(), Error> {
+        if arg.is_empty() {
+            return Err(Error::new("Empty key"))
+        }
+        self.key = Some(arg.clone());
+        Ok(())
+    }
+
+    fn parse_adaptive(&mut self, arg: &String) -> Result<(), Error> {
+        match arg.parse() {
+            Ok(threshold) if threshold > 0 => {
+                self.adaptive = Some(threshold);
+                Ok(())
+            },
+            _ => Err(Error::new("Invalid texture threshold, use 1 to 255")),
+        }
+    }
+
+    fn parse_max_length(&mut self, arg: &String) -> Result<(), Error> {
+        match arg.parse() {
+            Ok(length) => {
+                self.max_length = Some(length);
+                Ok(())
+            },
+            Err(_) => Err(Error::new("Invalid maximum message length")),
+        }
+    }
+
+}
+
+fn parse_ecc_depth(arg: &String) -> Result<u16, Error> {
+    match arg.parse() {
+        Ok(depth) if depth > 0 => Ok(depth),
+        _ => Err(Error::new("Invalid interleaving depth")),
+    }
+}
+
+fn read_message(input: &mut Read, message: &mut String) -> Result<(), Error> {
+    match input.read_to_string(message) {
+        Ok(_) => Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::InvalidData => Err(Error::new("Message is not UTF-8")),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
+fn read_key_file(path: &String) -> Result<String, Error> {
+    let mut file = try!(File::open(path));
+    let mut text = String::new();
+    try!(file.read_to_string(&mut text));
+    Ok(text)
+}
+
+fn read_env(name: &String) -> Result<String, Error> {
+    match env_var(name) {
+        Ok(value) => Ok(value),
+        Err(_) => Err(Error::new(&format!("Environment variable {} is not set or not UTF-8", name))),
+    }
+}
+
+/// Ask for the password on the terminal, without echo,
+/// twice when `confirm` is set
+fn prompt_password(confirm: bool) -> Result<String, Error> {
+    let mut tty = match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+        Ok(tty) => tty,
+        Err(_) => return Err(Error::new("No terminal to ask the password, use --password-env or --password-file")),
+    };
+    let password = try!(read_password(&mut tty, "Password: "));
+    if confirm && try!(read_password(&mut tty, "Confirm password: ")) != password {
+        return Err(Error::new("Passwords do not match"))
+    }
+    Ok(password)
+}
+
+fn read_password(tty: &mut File, prompt: &str) -> Result<String, Error> {
+    try!(tty.write_all(prompt.as_bytes()));
+    try!(tty.flush());
+
+    let fd = tty.as_raw_fd();
+    let mut saved: termios = unsafe { zeroed() };
+    if unsafe { tcgetattr(fd, &mut saved) } != 0 {
+        return Err(Error::new("Can't read terminal attributes"))
+    }
+    let mut silent = saved;
+    silent.c_lflag &= !ECHO;
+    silent.c_lflag |= ECHONL;
+    unsafe { tcsetattr(fd, TCSANOW, &silent) };
+
+    // read byte by byte, a buffered reader would eat what follows the line
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    let result = loop {
+        match tty.read(&mut byte) {
+            Ok(0) => break Ok(()),
+            Ok(_) if byte[0] == b'\n' => break Ok(()),
+            Ok(_) => line.push(byte[0]),
+            Err(err) => break Err(err),
+        }
+    };
+    unsafe { tcsetattr(fd, TCSANOW, &saved) };
+    try!(result);
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(password) => Ok(password),
+        Err(_) => Err(Error::new("Password is not UTF-8")),
+    }
 }
 
 fn assert_stdin_is_piped() -> Result<(), Error> {
@@ -97,4 +535,140 @@ fn assert_stdin_is_piped() -> Result<(), Error> {
             Err(Error::new("No input set, use `entrepixels --help` for more information"))
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::set_var;
+    use std::env::temp_dir;
+    use std::fs::File;
+    use std::fs::read_to_string;
+    use std::io::Write;
+    use std::path::Path;
+
+    use super::Args;
+
+    fn parse(args: &[&str]) -> Result<Args<'static>, String> {
+        match Args::from_env_args(args.iter().map(|arg| String::from(*arg))) {
+            Ok(args) => Ok(args),
+            Err(err) => Err(format!("{}", err)),
+        }
+    }
+
+    #[test]
+    fn message_sources() {
+        let image = temp_dir().join("entrepixels-args-image.bmp");
+        let message = temp_dir().join("entrepixels-args-message.txt");
+        write_file(&image, b"BM");
+        write_file(&message, b"from file\n");
+        let image = image.to_str().unwrap();
+        let message = message.to_str().unwrap();
+
+        assert_eq!(parse(&["hide", "-i", image, "--message-file", message]).unwrap().message, "from file\n");
+
+        set_var("ENTREPIXELS_TEST_MESSAGE", "from env");
+        assert_eq!(parse(&["hide", "-i", image, "--message-env", "ENTREPIXELS_TEST_MESSAGE"]).unwrap().message,
+                   "from env");
+        assert!(parse(&["hide", "-i", image, "--message-env", "ENTREPIXELS_TEST_UNSET"]).is_err());
+
+        // ambiguous sources
+        assert!(parse(&["hide", "-i", image, "-m", "a", "--message-env", "ENTREPIXELS_TEST_MESSAGE"]).is_err());
+        assert!(parse(&["hide", "-i", image, "--message-file", message, "-f", message]).is_err());
+        assert!(parse(&["hide", "--message-stdin", "-m", "a"]).is_err());
+        // stdin can't be both message and image
+        assert!(parse(&["hide", "--message-stdin"]).is_err());
+    }
+
+    #[test]
+    fn output_kept_on_error() {
+        let image = temp_dir().join("entrepixels-args-kept-image.bmp");
+        let output = temp_dir().join("entrepixels-args-kept-output.bmp");
+        write_file(&image, b"BM");
+        write_file(&output, b"kept");
+        let image = image.to_str().unwrap();
+
+        assert!(parse(&["hide", "-i", image, "-o", output.to_str().unwrap(), "-m", "a", "-f", image]).is_err());
+        assert_eq!(read_to_string(&output).unwrap(), "kept");
+    }
+
+    #[test]
+    fn password_sources() {
+        let image = temp_dir().join("entrepixels-args-password-image.bmp");
+        let password = temp_dir().join("entrepixels-args-password.txt");
+        write_file(&image, b"BM");
+        write_file(&password, b"first line\nsecond line\n");
+        let image = image.to_str().unwrap();
+        let password = password.to_str().unwrap();
+
+        let args = parse(&["show", "-i", image, "--password-file", password]).unwrap();
+        assert_eq!(args.password, Some(String::from("first line")));
+
+        set_var("ENTREPIXELS_TEST_PASSWORD", "from env");
+        let args = parse(&["show", "-i", image, "--password-env", "ENTREPIXELS_TEST_PASSWORD"]).unwrap();
+        assert_eq!(args.password, Some(String::from("from env")));
+
+        set_var("ENTREPIXELS_TEST_EMPTY", "");
+        assert!(parse(&["show", "-i", image, "--password-env", "ENTREPIXELS_TEST_EMPTY"]).is_err());
+        assert!(parse(&["show", "-i", image, "--password-file", password,
+                        "--password-env", "ENTREPIXELS_TEST_PASSWORD"]).is_err());
+
+        let recipient = format!("x25519:{}", "11".repeat(32));
+        assert_eq!(parse(&["hide", "-i", image, "--recipient", &recipient]).unwrap().recipients.len(), 1);
+        assert!(parse(&["hide", "-i", image, "--recipient", &recipient[1..]]).is_err());
+        assert!(parse(&["hide", "-i", image, "--recipient", &recipient,
+                        "--password-env", "ENTREPIXELS_TEST_PASSWORD"]).is_err());
+
+        // encryption and signing keys are not interchangeable
+        assert!(parse(&["show", "-i", image, "--verify-with", &recipient]).is_err());
+        let signer = format!("ed25519:{}", "11".repeat(32));
+        assert!(parse(&["show", "-i", image, "--verify-with", &signer]).unwrap().signer.is_some());
+        assert_eq!(parse(&["keygen", "--key-type", "ed25519"]).unwrap().key_type, "ed25519");
+        assert!(parse(&["keygen", "--key-type", "rsa"]).is_err());
+    }
+
+    #[test]
+    fn bits_per_channel() {
+        let image = temp_dir().join("entrepixels-args-bits-image.bmp");
+        write_file(&image, b"BM");
+        let image = image.to_str().unwrap();
+        let bits = |spec: &str| parse(&["show", "-i", image, "--bits-per-channel", spec]).map(|args| args.bits_per_channel);
+
+        assert_eq!(bits("3").unwrap(), [3, 3, 3, 3]);
+        assert_eq!(bits("r=2,g=2,b=3").unwrap(), [2, 2, 3, 1]);
+        assert_eq!(bits("2,a=1").unwrap(), [2, 2, 2, 1]);
+        assert!(bits("5").is_err());
+        assert!(bits("0").is_err());
+        assert!(bits("x=2").is_err());
+        assert!(bits("r=").is_err());
+
+        let channels = |spec: &str| parse(&["show", "-i", image, "--bits-per-channel", "2", "--channels", spec])
+            .map(|args| args.selected_bits_per_channel());
+        assert_eq!(channels("rgb").unwrap(), [2, 2, 2, 0]);
+        assert_eq!(channels("ba").unwrap(), [0, 0, 2, 2]);
+        assert!(channels("").is_err());
+        assert!(channels("rgbx").is_err());
+
+        let adaptive = |extra: &[&str]| parse(&[&["hide", "-i", image, "-m", "hi"], extra].concat())
+            .map(|args| args.embed_options().adaptive);
+        assert_eq!(adaptive(&["--adaptive", "16"]).unwrap(), Some(16));
+        assert_eq!(adaptive(&[]).unwrap(), None);
+        assert!(adaptive(&["--adaptive", "0"]).is_err());
+        assert!(adaptive(&["--adaptive", "256"]).is_err());
+        assert!(adaptive(&["--adaptive", "16", "--lsb-matching"]).is_err());
+    }
+
+    #[test]
+    fn convert_depth() {
+        let image = temp_dir().join("entrepixels-args-depth-image.bmp");
+        write_file(&image, b"BM");
+        let image = image.to_str().unwrap();
+
+        assert_eq!(parse(&["convert", "-i", image]).unwrap().depth, 24);
+        assert_eq!(parse(&["convert", "-i", image, "--depth", "32"]).unwrap().depth, 32);
+        assert!(parse(&["convert", "-i", image, "--depth", "16"]).is_err());
+    }
+
+    fn write_file(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+}
@@ -1,51 +1,57 @@
 pub const FILE_SIGNATURE: [u8; 2] = [b'B', b'M'];
 
-/// Position of offset to pixels field in header
-pub const OFFSET_TO_PIXELS_POSITION: usize = 0xA;
+/// Size of BITMAPFILEHEADER, the DIB header follows it
+pub const FILE_HEADER_SIZE: usize = 14;
 
-/// Position of size of DIB Header in header
-//pub const DIB_SIZE_POSITION: usize = 0xE;
+/// Size of a palette entry (RGBQUAD)
+pub const PALETTE_ENTRY_SIZE: usize = 4;
 
-/// Position of image width in pixels in header
-pub const WIDTH_POSITION: usize = 0x12;
+/// Size of a BITMAPCOREHEADER palette entry (RGBTRIPLE)
+pub const CORE_PALETTE_ENTRY_SIZE: usize = 3;
 
-/// Position of image height in pixels in header
-pub const HEIGHT_POSITION: usize = 0x16;
+/// Max depth of palette indexed pixels
+pub const MAX_INDEXED_DEPTH: u8 = 8;
 
-/*
-// It is not necessary, yet
-/// Position of planes field in header
-const PLANES_POSITION: usize = 0x1A;
-*/
+/// OS/2 1.x BITMAPCOREHEADER size
+pub const CORE_HEADER_SIZE: u32 = 12;
 
-/// Position of pixel depth field in header
-pub const PIXEL_DEPTH_POSITION: usize = 0x1C;
+/// OS/2 2.x BITMAPCOREHEADER2 size, it may be truncated to 16 bytes,
+/// its first 40 bytes have the same layout of BITMAPINFOHEADER
+pub const OS2_V2_HEADER_SIZE: u32 = 64;
+pub const OS2_V2_SHORT_HEADER_SIZE: u32 = 16;
 
-/// Position of compression type in header
-pub const COMPRESSION_POSITION: usize = 0x1E;
+/// BITMAPINFOHEADER size
+pub const INFO_HEADER_SIZE: u32 = 40;
 
-/// Position of raw bitmap data size in bytes with padding in header
-pub const RAW_BITMAP_DATA_SIZE_POSITION: usize = 0x22;
+/// BITMAPV2INFOHEADER size (RGB masks inside header)
+pub const V2_INFO_HEADER_SIZE: u32 = 52;
 
-/**
- * for compression of type BI_BITFIELDS,
- * there are bit masks for rgba layers
- */
+/// BITMAPV3INFOHEADER size (RGBA masks inside header)
+pub const V3_INFO_HEADER_SIZE: u32 = 56;
 
-/// Red mask
-pub const RED_MASK_POSITION: usize = 0x36;
+/// BITMAPV4HEADER size
+pub const V4_HEADER_SIZE: u32 = 108;
 
-/// Green mask
-pub const GREEN_MASK_POSITION: usize = 0x3A;
+/// BITMAPV5HEADER size
+pub const V5_HEADER_SIZE: u32 = 124;
 
-/// Blue mask
-pub const BLUE_MASK_POSITION: usize = 0x3E;
+/// Resolution written in new bitmaps (72 DPI)
+pub const DEFAULT_PIXELS_PER_METER: i32 = 2835;
 
-/// Alpha mask
-pub const ALPHA_MASK_POSITION: usize = 0x42;
+/// LCS_sRGB color space type of BITMAPV4HEADER
+pub const LCS_SRGB: u32 = 0x73524742;
 
 /// BI_RGB compression type
 pub const BI_RGB_COMPRESSION: u32 = 0;
 
+/// BI_RLE8 compression type
+pub const BI_RLE8_COMPRESSION: u32 = 1;
+
+/// BI_RLE4 compression type
+pub const BI_RLE4_COMPRESSION: u32 = 2;
+
 /// BI_BITFIELDS compression type
-pub const BI_BITFIELDS_COMPRESSION: u32 = 3;
\ No newline at end of file
+pub const BI_BITFIELDS_COMPRESSION: u32 = 3;
+
+/// BI_ALPHABITFIELDS compression type
+pub const BI_ALPHABITFIELDS_COMPRESSION: u32 = 6;
\ No newline at end of file
@@ -0,0 +1,485 @@
+// Typed BMP headers, parsed from and serialized back to the exact
+// same bytes, so a file keeps everything but its pixels when written
+use std::vec::Vec;
+
+use super::consts;
+use super::super::error::Error;
+use super::super::util::read_le_u16;
+use super::super::util::read_le_u32;
+use super::super::util::write_le_u16;
+use super::super::util::write_le_u32;
+
+/// BITMAPFILEHEADER
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BmpFileHeader {
+    /// size of the whole file in bytes
+    pub file_size: u32,
+    pub reserved1: u16,
+    pub reserved2: u16,
+    /// offset of pixel array from start of file
+    pub offset: u32,
+}
+
+/// DIB header versions, told apart by header size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DibVersion {
+    /// OS/2 1.x BITMAPCOREHEADER
+    Core,
+    /// OS/2 2.x BITMAPCOREHEADER2, full or truncated
+    Os2,
+    /// BITMAPINFOHEADER
+    Info,
+    /// BITMAPV2INFOHEADER
+    V2Info,
+    /// BITMAPV3INFOHEADER
+    V3Info,
+    /// BITMAPV4HEADER
+    V4,
+    /// BITMAPV5HEADER
+    V5,
+}
+
+/// BITMAPV4HEADER color space fields
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColorSpace {
+    pub cs_type: u32,
+    /// red, green and blue CIEXYZ endpoints, in 2.30 fixed point
+    pub endpoints: [i32; 9],
+    pub gamma_red: u32,
+    pub gamma_green: u32,
+    pub gamma_blue: u32,
+}
+
+/// BITMAPV5HEADER rendering intent and ICC profile fields
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub intent: u32,
+    /// profile offset from start of DIB header
+    pub data: u32,
+    pub size: u32,
+    pub reserved: u32,
+}
+
+/// OS/2 2.x fields following the ones shared with BITMAPINFOHEADER
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Os2Extension {
+    pub units: u16,
+    pub reserved: u16,
+    pub recording: u16,
+    pub rendering: u16,
+    pub size1: u32,
+    pub size2: u32,
+    pub color_encoding: u32,
+    pub identifier: u32,
+}
+
+/// Any DIB header version, fields absent from a version are zero
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DibHeader {
+    /// header size in bytes, it tells the header version
+    pub size: u32,
+    pub width: i32,
+    /// negative for top-down bitmaps
+    pub height: i32,
+    pub planes: u16,
+    /// bits per pixel
+    pub depth: u16,
+    pub compression: u32,
+    /// pixel array size, may be zero for uncompressed images
+    pub image_size: u32,
+    pub x_pixels_per_meter: i32,
+    pub y_pixels_per_meter: i32,
+    pub colors_used: u32,
+    pub colors_important: u32,
+    /// channel masks, inside header from BITMAPV2INFOHEADER on,
+    /// right after a BITMAPINFOHEADER with bit fields compression
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+    pub alpha_mask: u32,
+    pub color_space: ColorSpace,
+    pub profile: Profile,
+    pub os2: Os2Extension,
+}
+
+/// Little endian fields read one after another
+struct FieldReader<'a> {
+    buf: &'a [u8],
+    position: usize,
+}
+
+impl<'a> FieldReader<'a> {
+
+    fn new(buf: &'a [u8]) -> FieldReader<'a> {
+        FieldReader {
+            buf: buf,
+            position: 0,
+        }
+    }
+
+    fn u16(&mut self) -> u16 {
+        self.position += 2;
+        read_le_u16(self.buf, self.position - 2)
+    }
+
+    fn u32(&mut self) -> u32 {
+        self.position += 4;
+        read_le_u32(self.buf, self.position - 4)
+    }
+
+    fn i32(&mut self) -> i32 {
+        self.u32() as i32
+    }
+}
+
+/// Little endian fields written one after another
+struct FieldWriter {
+    buf: Vec<u8>,
+}
+
+impl FieldWriter {
+
+    fn new() -> FieldWriter {
+        FieldWriter {
+            buf: Vec::new(),
+        }
+    }
+
+    fn u16(&mut self, n: u16) {
+        let position = self.buf.len();
+        self.buf.extend_from_slice(&[0; 2]);
+        write_le_u16(&mut self.buf, position, n);
+    }
+
+    fn u32(&mut self, n: u32) {
+        let position = self.buf.len();
+        self.buf.extend_from_slice(&[0; 4]);
+        write_le_u32(&mut self.buf, position, n);
+    }
+
+    fn i32(&mut self, n: i32) {
+        self.u32(n as u32)
+    }
+}
+
+impl BmpFileHeader {
+
+    pub fn parse(data: &[u8]) -> Result<BmpFileHeader, Error> {
+        if data.len() < consts::FILE_HEADER_SIZE {
+            return Err(Error::new("Invalid File"))
+        }
+        if data[..2] != consts::FILE_SIGNATURE {
+            return Err(Error::new("Invalid file signature"))
+        }
+
+        let mut fields = FieldReader::new(&data[2..]);
+        Ok(BmpFileHeader {
+            file_size: fields.u32(),
+            reserved1: fields.u16(),
+            reserved2: fields.u16(),
+            offset: fields.u32(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut fields = FieldWriter::new();
+        fields.buf.extend_from_slice(&consts::FILE_SIGNATURE);
+        fields.u32(self.file_size);
+        fields.u16(self.reserved1);
+        fields.u16(self.reserved2);
+        fields.u32(self.offset);
+        fields.buf
+    }
+}
+
+impl DibVersion {
+
+    pub fn from_size(size: u32) -> Result<DibVersion, Error> {
+        Ok(match size {
+            consts::CORE_HEADER_SIZE => DibVersion::Core,
+            consts::OS2_V2_SHORT_HEADER_SIZE |
+            consts::OS2_V2_HEADER_SIZE => DibVersion::Os2,
+            consts::INFO_HEADER_SIZE => DibVersion::Info,
+            consts::V2_INFO_HEADER_SIZE => DibVersion::V2Info,
+            consts::V3_INFO_HEADER_SIZE => DibVersion::V3Info,
+            consts::V4_HEADER_SIZE => DibVersion::V4,
+            consts::V5_HEADER_SIZE => DibVersion::V5,
+            _ => return Err(Error::new("Unsupported DIB header")),
+        })
+    }
+}
+
+impl DibHeader {
+
+    /// Parse a DIB header, and the bit fields masks following it,
+    /// from the start of `data`
+    pub fn parse(data: &[u8]) -> Result<DibHeader, Error> {
+        if data.len() < 4 {
+            return Err(Error::new("Truncated DIB header"))
+        }
+        let size = read_le_u32(data, 0);
+        let version = try!(DibVersion::from_size(size));
+        if data.len() < size as usize {
+            return Err(Error::new("Truncated DIB header"))
+        }
+
+        let mut header = DibHeader::default();
+        header.size = size;
+
+        if version == DibVersion::Core {
+            let mut fields = FieldReader::new(&data[4..]);
+            header.width = fields.u16() as i32;
+            header.height = fields.u16() as i32;
+            header.planes = fields.u16();
+            header.depth = fields.u16();
+            return Ok(header)
+        }
+
+        // fields left out of a truncated OS/2 2.x header are zero
+        let mut raw = [0u8; consts::V5_HEADER_SIZE as usize];
+        raw[..size as usize].copy_from_slice(&data[..size as usize]);
+
+        let mut fields = FieldReader::new(&raw[4..]);
+        header.width = fields.i32();
+        header.height = fields.i32();
+        header.planes = fields.u16();
+        header.depth = fields.u16();
+        header.compression = fields.u32();
+        header.image_size = fields.u32();
+        header.x_pixels_per_meter = fields.i32();
+        header.y_pixels_per_meter = fields.i32();
+        header.colors_used = fields.u32();
+        header.colors_important = fields.u32();
+
+        if version == DibVersion::Os2 {
+            header.os2 = Os2Extension {
+                units: fields.u16(),
+                reserved: fields.u16(),
+                recording: fields.u16(),
+                rendering: fields.u16(),
+                size1: fields.u32(),
+                size2: fields.u32(),
+                color_encoding: fields.u32(),
+                identifier: fields.u32(),
+            };
+            return Ok(header)
+        }
+
+        header.red_mask = fields.u32();
+        header.green_mask = fields.u32();
+        header.blue_mask = fields.u32();
+        header.alpha_mask = fields.u32();
+        header.color_space.cs_type = fields.u32();
+        for endpoint in header.color_space.endpoints.iter_mut() {
+            *endpoint = fields.i32();
+        }
+        header.color_space.gamma_red = fields.u32();
+        header.color_space.gamma_green = fields.u32();
+        header.color_space.gamma_blue = fields.u32();
+        header.profile = Profile {
+            intent: fields.u32(),
+            data: fields.u32(),
+            size: fields.u32(),
+            reserved: fields.u32(),
+        };
+
+        let extra_masks = header.extra_masks_len();
+        if extra_masks > 0 {
+            let end = size as usize + extra_masks;
+            if data.len() < end {
+                return Err(Error::new("Truncated bit fields masks"))
+            }
+            let first = header.first_extra_mask();
+            let mut fields = FieldReader::new(&data[size as usize..end]);
+            for mask in header.masks_mut().iter_mut().skip(first).take(extra_masks / 4) {
+                **mask = fields.u32();
+            }
+        }
+
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut fields = FieldWriter::new();
+        fields.u32(self.size);
+
+        if self.version() == DibVersion::Core {
+            fields.u16(self.width as u16);
+            fields.u16(self.height as u16);
+            fields.u16(self.planes);
+            fields.u16(self.depth);
+            return fields.buf
+        }
+
+        fields.i32(self.width);
+        fields.i32(self.height);
+        fields.u16(self.planes);
+        fields.u16(self.depth);
+        fields.u32(self.compression);
+        fields.u32(self.image_size);
+        fields.i32(self.x_pixels_per_meter);
+        fields.i32(self.y_pixels_per_meter);
+        fields.u32(self.colors_used);
+        fields.u32(self.colors_important);
+
+        if self.version() == DibVersion::Os2 {
+            fields.u16(self.os2.units);
+            fields.u16(self.os2.reserved);
+            fields.u16(self.os2.recording);
+            fields.u16(self.os2.rendering);
+            fields.u32(self.os2.size1);
+            fields.u32(self.os2.size2);
+            fields.u32(self.os2.color_encoding);
+            fields.u32(self.os2.identifier);
+            fields.buf.truncate(self.size as usize);
+            return fields.buf
+        }
+
+        let masks = [self.red_mask, self.green_mask, self.blue_mask, self.alpha_mask];
+        for mask in masks.iter() {
+            fields.u32(*mask);
+        }
+        fields.u32(self.color_space.cs_type);
+        for endpoint in self.color_space.endpoints.iter() {
+            fields.i32(*endpoint);
+        }
+        fields.u32(self.color_space.gamma_red);
+        fields.u32(self.color_space.gamma_green);
+        fields.u32(self.color_space.gamma_blue);
+        fields.u32(self.profile.intent);
+        fields.u32(self.profile.data);
+        fields.u32(self.profile.size);
+        fields.u32(self.profile.reserved);
+        fields.buf.truncate(self.size as usize);
+
+        let first = self.first_extra_mask();
+        for mask in masks[first..first + self.extra_masks_len() / 4].iter() {
+            fields.u32(*mask);
+        }
+
+        fields.buf
+    }
+
+    /// Header version, `size` must have been checked by `DibVersion::from_size`
+    #[inline(always)]
+    pub fn version(&self) -> DibVersion {
+        DibVersion::from_size(self.size).unwrap_or(DibVersion::Info)
+    }
+
+    /// Length of header plus the masks following it
+    #[inline(always)]
+    pub fn encoded_len(&self) -> usize {
+        self.size as usize + self.extra_masks_len()
+    }
+
+    /// Bytes of channel masks stored right after the header,
+    /// as the header itself has no room for them
+    pub fn extra_masks_len(&self) -> usize {
+        match (self.version(), self.compression) {
+            (DibVersion::Info, consts::BI_BITFIELDS_COMPRESSION) => 12,
+            (DibVersion::Info, consts::BI_ALPHABITFIELDS_COMPRESSION) => 16,
+            (DibVersion::V2Info, consts::BI_ALPHABITFIELDS_COMPRESSION) => 4,
+            _ => 0,
+        }
+    }
+
+    /// Index of the first mask (red, green, blue, alpha) stored after the header
+    fn first_extra_mask(&self) -> usize {
+        match self.version() {
+            DibVersion::V2Info => 3,
+            _ => 0,
+        }
+    }
+
+    fn masks_mut(&mut self) -> [&mut u32; 4] {
+        [&mut self.red_mask, &mut self.green_mask, &mut self.blue_mask, &mut self.alpha_mask]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BmpFileHeader;
+    use super::DibHeader;
+    use super::DibVersion;
+    use super::super::consts;
+
+    /// A header with every byte set, so any field lost shows up
+    fn header_bytes(size: u32) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..size as usize + 16).map(|i| (i * 7 + 1) as u8).collect();
+        data[0] = size as u8;
+        data[1] = 0;
+        data[2] = 0;
+        data[3] = 0;
+        data
+    }
+
+    #[test]
+    fn file_header_round_trip() {
+        let data = [b'B', b'M', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let header = BmpFileHeader::parse(&data).unwrap();
+        assert_eq!(header.offset, 0x0c0b0a09);
+        assert_eq!(&header.to_bytes()[..], &data[..]);
+
+        assert!(BmpFileHeader::parse(&data[..13]).is_err());
+        assert!(BmpFileHeader::parse(b"MB\0\0\0\0\0\0\0\0\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn dib_header_round_trip() {
+        let sizes = [
+            consts::CORE_HEADER_SIZE,
+            consts::OS2_V2_SHORT_HEADER_SIZE,
+            consts::OS2_V2_HEADER_SIZE,
+            consts::INFO_HEADER_SIZE,
+            consts::V2_INFO_HEADER_SIZE,
+            consts::V3_INFO_HEADER_SIZE,
+            consts::V4_HEADER_SIZE,
+            consts::V5_HEADER_SIZE,
+        ];
+        for size in sizes.iter() {
+            let data = header_bytes(*size);
+            let header = DibHeader::parse(&data).unwrap();
+            assert_eq!(header.encoded_len(), *size as usize);
+            assert_eq!(&header.to_bytes()[..], &data[..*size as usize]);
+            assert_eq!(DibHeader::parse(&header.to_bytes()).unwrap(), header);
+        }
+
+        assert!(DibHeader::parse(&header_bytes(44)).is_err());
+        assert!(DibHeader::parse(&header_bytes(consts::V5_HEADER_SIZE)[..100]).is_err());
+    }
+
+    #[test]
+    fn dib_header_fields() {
+        let data = header_bytes(consts::CORE_HEADER_SIZE);
+        let header = DibHeader::parse(&data).unwrap();
+        assert_eq!(header.version(), DibVersion::Core);
+        assert_eq!(header.width, 0x241d);
+        assert_eq!(header.compression, 0);
+
+        // truncated OS/2 2.x header
+        let data = header_bytes(consts::OS2_V2_SHORT_HEADER_SIZE);
+        let header = DibHeader::parse(&data).unwrap();
+        assert_eq!(header.version(), DibVersion::Os2);
+        assert_eq!(header.depth, 0x6a63);
+        assert_eq!(header.compression, 0);
+    }
+
+    #[test]
+    fn masks_after_info_header() {
+        for &(compression, masks_len) in &[(consts::BI_BITFIELDS_COMPRESSION, 12),
+                                           (consts::BI_ALPHABITFIELDS_COMPRESSION, 16)] {
+            let mut data = header_bytes(consts::INFO_HEADER_SIZE);
+            data[16] = compression as u8;
+            data[17] = 0;
+            data[18] = 0;
+            data[19] = 0;
+            let header = DibHeader::parse(&data).unwrap();
+            assert_eq!(header.encoded_len(), 40 + masks_len);
+            assert_eq!(header.red_mask, 0x2e272019);
+            assert_eq!(header.alpha_mask != 0, masks_len == 16);
+            assert_eq!(&header.to_bytes()[..], &data[..40 + masks_len]);
+
+            assert!(DibHeader::parse(&data[..40 + masks_len - 1]).is_err());
+        }
+    }
+}
@@ -1,6 +1,5 @@
 use std::io::Seek;
 use std::io::SeekFrom;
-use std::io::Read;
 use std::iter::Iterator;
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -9,75 +8,248 @@ use std::vec::Vec;
 use std::mem;
 
 mod consts;
+mod header;
+mod palette;
 mod pixel;
+mod rle;
 
+pub use self::header::BmpFileHeader;
+pub use self::header::DibHeader;
+pub use self::header::DibVersion;
+pub use self::palette::Color;
+pub use self::palette::LuminanceOrder;
+pub use self::palette::Palette;
 pub use self::pixel::PixelFormat;
 pub use self::pixel::Pixel;
+pub use self::pixel::channel_level;
 use super::error::Error;
 use super::io::bitbuf::BitBuf;
-
-/// Provavelmente irei excluir estes imports
-use super::util::read_le_u16;
-use super::util::read_le_u32;
+use self::rle::Rle;
 
 type SharedData = Rc<RefCell<BitBuf<Cursor<Vec<u8>>>>>;
 
 pub struct Bitmap {
     // origin
     data: SharedData,
-    // data offset
-    offset: u32,
-    // image dimensions
-    width: u32,
-    height: u32,
+    // headers, as found in `data`
+    file_header: BmpFileHeader,
+    dib_header: DibHeader,
     // row length in bits (with padding)
     row_length: u32,
     // pixel format
     pixel_format: PixelFormat,
+    // color table of indexed images
+    palette: Palette,
+    // compression of RLE pixel arrays, kept expanded in `data`
+    rle: Option<Rle>,
 }
 
 impl Bitmap {
 
     pub fn try_from(data: Vec<u8>) -> Result<Bitmap, Error> {
 
-        let mut data = Cursor::new(data);
-
-        let mut signature = [0; 2];
+        let file_header = try!(BmpFileHeader::parse(&data));
+        let dib_header = try!(DibHeader::parse(&data[consts::FILE_HEADER_SIZE..]));
 
-        try!(data.read_exact(&mut signature));
+        // Image dimensions
+        let offset = file_header.offset;
+        let width = dib_header.width;
+        let height = dib_header.height;
 
-        if signature != consts::FILE_SIGNATURE {
-            return Err(Error::new("Invalid file signature"))
+        if width <= 0 || height == 0 || height == i32::MIN {
+            return Err(Error::new("Invalid image dimensions"))
         }
+        let width = width as u32;
 
-        let header = try!(Self::read_header(&mut data));
+        let data_size = dib_header.image_size;
 
-        // Image dimensions
-        let offset = read_le_u32(&header, consts::OFFSET_TO_PIXELS_POSITION);
-        let width = read_le_u32(&header, consts::WIDTH_POSITION);
-        let height = read_le_u32(&header, consts::HEIGHT_POSITION);
+        let pixel_format = try!(Self::read_pixel_format(&dib_header));
+
+        let palette_position = consts::FILE_HEADER_SIZE + dib_header.encoded_len();
+        let palette = try!(Self::read_palette(&data, palette_position, &file_header, &dib_header));
+
+        let rows = (height as i64).abs() as u64;
+        let row_length = Self::row_length(width, pixel_format.depth);
+        let headers_end = (palette_position + palette.encoded_len()) as u64;
+
+        let compression = dib_header.compression;
+        let (mut data, rle) = if compression == consts::BI_RLE8_COMPRESSION ||
+                                 compression == consts::BI_RLE4_COMPRESSION {
+            if height < 0 {
+                return Err(Error::new("Compressed bitmaps can't be top-down"))
+            }
+            let file_size = data.len() as u64;
+            // size may be omitted when pixels go up to end of file
+            let data_size = if data_size == 0 && file_size > offset as u64 {
+                (file_size - offset as u64) as u32
+            } else {
+                data_size
+            };
+            try!(Self::check_pixel_array(file_size, headers_end, offset as u64, data_size as u64, 0));
 
-        let data_size = read_le_u32(&header, consts::RAW_BITMAP_DATA_SIZE_POSITION);
+            let mut file = data;
+            let trailer = file.split_off((offset + data_size) as usize);
+            let pixels = try!(rle::decode(&file[offset as usize..], width, rows as u32,
+                                          pixel_format.depth, row_length as usize));
+            file.truncate(offset as usize);
+            file.extend(pixels);
+            try!(Self::check_pixel_array(file.len() as u64, headers_end, offset as u64, row_length * rows, 0));
 
-        let pixel_format = try!(Self::read_pixel_format(&header));
+            (Cursor::new(file), Some(Rle {
+                compression: compression,
+                size: data_size,
+                trailer: trailer,
+                expand: false,
+            }))
+        } else {
+            try!(Self::check_pixel_array(
+                data.len() as u64,
+                headers_end,
+                offset as u64,
+                row_length * rows,
+                data_size as u64));
+            (Cursor::new(data), None)
+        };
 
         try!(data.seek(SeekFrom::Start(0)));
 
         Ok(Bitmap {
             // data
             data: Rc::new(RefCell::new(BitBuf::from(data))),
-            // data offset
-            offset: offset,
-            // image dimensions
-            width: width,
-            height: height,
+            // headers
+            file_header: file_header,
+            dib_header: dib_header,
             // row length in bits
-            row_length: data_size / height * 8,
+            row_length: row_length as u32 * 8,
             // pixel format
             pixel_format: pixel_format,
+            // color table
+            palette: palette,
+            // RLE compression
+            rle: rle,
         })
     }
 
+    /// Create a bottom-up `width` x `height` image with all pixels set to 0
+    ///
+    /// `palette` is the color table of 1, 4 and 8 bits pixels, and must be
+    /// empty for other depths. The padding mask of `pixel_format` is ignored.
+    pub fn new(width: u32, height: u32, pixel_format: &PixelFormat, palette: &[Color]) -> Result<Bitmap, Error> {
+        Self::filled(width, height, pixel_format, palette, 0)
+    }
+
+    /// Create a bottom-up image with all pixels set to `value`,
+    /// a palette index for indexed images
+    pub fn filled(width: u32, height: u32, pixel_format: &PixelFormat, palette: &[Color], value: u32) -> Result<Bitmap, Error> {
+        if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+            return Err(Error::new("Invalid image dimensions"))
+        }
+
+        let depth = pixel_format.depth;
+        let indexed = depth <= consts::MAX_INDEXED_DEPTH;
+        if (indexed && (palette.is_empty() || palette.len() > 1 << depth)) || (!indexed && !palette.is_empty()) {
+            return Err(Error::new("Invalid palette size"))
+        }
+        let palette = if indexed { Palette::new(palette) } else { Palette::default() };
+
+        let masks = (pixel_format.red_mask, pixel_format.green_mask, pixel_format.blue_mask, pixel_format.alpha_mask);
+        let default_masks = match depth {
+            16 => (0x7c00u32, 0x03e0u32, 0x001fu32, 0u32),
+            24 | 32 => (0xff0000u32, 0xff00u32, 0xffu32, 0u32),
+            _ => (0u32, 0u32, 0u32, 0u32),
+        };
+
+        // plain BITMAPINFOHEADER whenever possible, it is the most widely read,
+        // alpha masks need a BITMAPV4HEADER
+        let mut dib_header = DibHeader {
+            size: if masks.3 != 0 { consts::V4_HEADER_SIZE } else { consts::INFO_HEADER_SIZE },
+            width: width as i32,
+            height: height as i32,
+            planes: 1,
+            depth: depth as u16,
+            compression: if masks == default_masks { consts::BI_RGB_COMPRESSION } else { consts::BI_BITFIELDS_COMPRESSION },
+            x_pixels_per_meter: consts::DEFAULT_PIXELS_PER_METER,
+            y_pixels_per_meter: consts::DEFAULT_PIXELS_PER_METER,
+            colors_used: palette.len() as u32,
+            ..DibHeader::default()
+        };
+        if dib_header.compression != consts::BI_RGB_COMPRESSION {
+            dib_header.red_mask = masks.0;
+            dib_header.green_mask = masks.1;
+            dib_header.blue_mask = masks.2;
+            dib_header.alpha_mask = masks.3;
+        }
+        if dib_header.version() == DibVersion::V4 {
+            dib_header.color_space.cs_type = consts::LCS_SRGB;
+        }
+
+        let row_length = Self::row_length(width, depth);
+        let size = row_length * height as u64;
+        let offset = (consts::FILE_HEADER_SIZE + dib_header.encoded_len() + palette.encoded_len()) as u64;
+        try!(Self::check_pixel_array(offset + size, offset, offset, size, 0));
+        dib_header.image_size = size as u32;
+
+        let file_header = BmpFileHeader {
+            file_size: (offset + size) as u32,
+            offset: offset as u32,
+            ..BmpFileHeader::default()
+        };
+
+        let mut data = file_header.to_bytes();
+        data.extend(dib_header.to_bytes());
+        data.extend(palette.to_bytes());
+        let row = Self::pixel_row(width, depth, row_length as usize, value);
+        for _ in 0..height {
+            data.extend_from_slice(&row);
+        }
+
+        // going through the parser checks the pixel format
+        let bitmap = try!(Self::try_from(data));
+        {
+            let format = bitmap.pixel_format();
+            if (format.red_mask, format.green_mask, format.blue_mask, format.alpha_mask) != masks {
+                return Err(Error::new("Unsupported pixel format"))
+            }
+        }
+        Ok(bitmap)
+    }
+
+    /// Copy of this image with `depth` (24 or 32) bits RGB pixels, with
+    /// an alpha channel at 32 bits when this image has one
+    pub fn convert(&mut self, depth: u8) -> Result<Bitmap, Error> {
+        if depth != 24 && depth != 32 {
+            return Err(Error::new("Unsupported pixel depth"))
+        }
+        let source = self.pixel_format.clone();
+        let palette = self.palette.clone();
+        let format = PixelFormat {
+            depth: depth,
+            red_mask: 0xff0000,
+            green_mask: 0xff00,
+            blue_mask: 0xff,
+            alpha_mask: if depth == 32 && source.alpha_mask != 0 { 0xff000000 } else { 0 },
+            padding_mask: 0,
+        };
+        let mut bitmap = try!(Self::new(self.width(), self.height(), &format, &[]));
+
+        for (mut pixel, mut converted) in self.pixels_from(&None).zip(bitmap.pixels_from(&None)) {
+            let value = try!(pixel.value());
+            let (red, green, blue) = if palette.is_empty() {
+                (channel_level(value, source.red_mask),
+                 channel_level(value, source.green_mask),
+                 channel_level(value, source.blue_mask))
+            } else {
+                match palette.colors().get(value as usize) {
+                    Some(color) => (color.red, color.green, color.blue),
+                    None => return Err(Error::new("Pixel out of palette")),
+                }
+            };
+            let alpha = if format.alpha_mask != 0 { channel_level(value, source.alpha_mask) } else { 0 };
+            try!(converted.set_value((alpha as u32) << 24 | (red as u32) << 16 | (green as u32) << 8 | blue as u32));
+        }
+        Ok(bitmap)
+    }
+
     pub fn try_unwrap_data(mut this: Self) -> Result<Vec<u8>, Bitmap> {
 
         let mut shared_data: SharedData = Rc::new(RefCell::new(BitBuf::from(Cursor::new(Vec::new()))));
@@ -86,7 +258,11 @@ impl Bitmap {
 
         match Rc::try_unwrap(shared_data) {
             Ok(cell) => {
-                Ok(cell.into_inner().into_inner().into_inner())
+                let data = cell.into_inner().into_inner().into_inner();
+                Ok(match this.rle {
+                    Some(ref rle) => this.compress(data, rle),
+                    None => data,
+                })
             },
             Err(data) => {
                 this.data = data;
@@ -105,61 +281,259 @@ impl Bitmap {
         Pixels::new(self, pos)
     }
 
+    /// Pixel at `column` and `row`, counted from the bottom-left corner
+    pub fn pixel(&self, column: u32, row: u32) -> Pixel {
+        let storage_row = if self.is_top_down() { self.height() - 1 - row } else { row };
+        let offset = self.file_header.offset * 8 +
+                        storage_row * self.row_length +
+                        column * self.pixel_format.depth as u32;
+
+        Pixel::new(self.data.clone(), offset, column, row, self.pixel_format.clone())
+    }
+
+    #[inline(always)]
+    pub fn width(&self) -> u32 {
+        self.dib_header.width as u32
+    }
+
+    /// Number of rows, whatever the storage order is
+    #[inline(always)]
+    pub fn height(&self) -> u32 {
+        (self.dib_header.height as i64).abs() as u32
+    }
+
+    /// Write RLE compressed images back uncompressed
+    pub fn set_uncompressed_output(&mut self, uncompressed: bool) {
+        if let Some(ref mut rle) = self.rle {
+            rle.expand = uncompressed;
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_top_down(&self) -> bool {
+        self.dib_header.height < 0
+    }
+
     #[inline(always)]
     pub fn pixel_format(& self) -> &PixelFormat {
         & self.pixel_format
     }
 
+    /// Color table, empty if pixels are not palette indexes
+    #[inline(always)]
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
     #[inline(always)]
     pub fn flush(&mut self) -> Result<(), Error> {
         try!(self.data.borrow_mut().flush());
         Ok(())
     }
 
-    /// Peek 256 bytes from bitmap
+    /// Replace the expanded pixel array in `data` with the one to be
+    /// written, compressed back or not, and fix sizes in headers
+    fn compress(&self, mut data: Vec<u8>, rle: &Rle) -> Vec<u8> {
+        let offset = self.file_header.offset as usize;
+        let pixels = data.split_off(offset);
+
+        let (compression, pixels) = if rle.expand {
+            (consts::BI_RGB_COMPRESSION, pixels)
+        } else {
+            let row_length = self.row_length as usize / 8;
+            (rle.compression, rle::encode(&pixels, self.width(), self.height(), self.pixel_format.depth, row_length))
+        };
+        debug!("pixel array size = {} (was {})", pixels.len(), rle.size);
+
+        let mut file_header = self.file_header.clone();
+        let mut dib_header = self.dib_header.clone();
+        dib_header.compression = compression;
+        dib_header.image_size = pixels.len() as u32;
+        file_header.file_size = (offset + pixels.len() + rle.trailer.len()) as u32;
+
+        // a color profile after pixels moves along with the end of pixel array
+        if dib_header.version() == DibVersion::V5 {
+            let profile = dib_header.profile.data as usize;
+            if profile + consts::FILE_HEADER_SIZE >= offset + rle.size as usize {
+                dib_header.profile.data = (profile + pixels.len() - rle.size as usize) as u32;
+            }
+        }
+
+        let mut headers = file_header.to_bytes();
+        headers.extend(dib_header.to_bytes());
+        headers.extend(self.palette.to_bytes());
+        data[..headers.len()].copy_from_slice(&headers);
+
+        data.extend(pixels);
+        data.extend_from_slice(&rle.trailer);
+        data
+    }
+
+    /// Read the color table of an indexed image starting at `position`
+    fn read_palette(data: &[u8], position: usize, file_header: &BmpFileHeader, dib_header: &DibHeader) -> Result<Palette, Error> {
+        if dib_header.depth as u8 > consts::MAX_INDEXED_DEPTH {
+            return Ok(Palette::default())
+        }
+
+        let max_colors = 1usize << dib_header.depth;
+        let (colors, entry_size) = if dib_header.version() == DibVersion::Core {
+            // BITMAPCOREHEADER has no colors count, the palette is full,
+            // but some writers store less colors right before pixels
+            let offset = file_header.offset as usize;
+            let entry_size = consts::CORE_PALETTE_ENTRY_SIZE;
+            if offset > position {
+                (::std::cmp::min(max_colors, (offset - position) / entry_size), entry_size)
+            } else {
+                (max_colors, entry_size)
+            }
+        } else {
+            match dib_header.colors_used as usize {
+                0 => (max_colors, consts::PALETTE_ENTRY_SIZE),
+                n if n <= max_colors => (n, consts::PALETTE_ENTRY_SIZE),
+                _ => return Err(Error::new("Invalid palette size")),
+            }
+        };
+        debug!("palette colors = {}", colors);
+
+        if data.len() < position {
+            return Err(Error::new("Truncated palette"))
+        }
+        Palette::parse(&data[position..], colors, entry_size)
+    }
+
+    /// A row of `width` pixels set to `value`, padded to `row_length` bytes
+    fn pixel_row(width: u32, depth: u8, row_length: usize, value: u32) -> Vec<u8> {
+        let mut row = vec![0u8; row_length];
+        let depth = depth as usize;
+        for column in 0..width as usize {
+            let bit = column * depth;
+            if depth < 8 {
+                // first pixel in the high bits of a byte
+                let value = value as u8 & !(0xffu8 << depth);
+                row[bit / 8] |= value << (8 - depth - bit % 8);
+            } else {
+                // little endian
+                for i in 0..depth / 8 {
+                    row[bit / 8 + i] = (value >> (8 * i)) as u8;
+                }
+            }
+        }
+        row
+    }
+
+    /// Row length in bytes, rows are padded to a multiple of 4 bytes
     #[inline(always)]
-    fn read_header<T: Read + Seek>(buf: &mut T) -> Result<[u8;  256], Error> {
-        let mut header = [0u8; 256];
+    fn row_length(width: u32, depth: u8) -> u64 {
+        (width as u64 * depth as u64).div_ceil(32) * 4
+    }
+
+    /// Check that the pixel array, of `size` bytes, fits in a file of
+    /// `file_size` bytes between the end of headers and the end of file
+    /// and agrees with the size declared in header
+    fn check_pixel_array(file_size: u64, headers_end: u64, offset: u64, size: u64, declared_size: u64) -> Result<(), Error> {
+        debug!("pixel array size = {}, declared = {}", size, declared_size);
 
-        try!(buf.seek(SeekFrom::Start(0)));
-        if try!(buf.read(&mut header)) < 14 {
-            return Err(Error::new("Invalid File"))
+        if offset < headers_end {
+            return Err(Error::new("Pixel array overlaps headers"))
         }
-        if header[0] != b'B' || header[1] != b'M' {
-            return Err(Error::new("Invalid File"))
+        // bit offsets of pixels are kept in u32
+        if (offset + size) * 8 > u32::MAX as u64 {
+            return Err(Error::new("Image too large"))
         }
-        Ok(header)
+        if offset + size > file_size {
+            return Err(Error::new("Truncated pixel array"))
+        }
+        // zero is allowed for uncompressed images, bigger values
+        // are written by some tools with extra padding
+        if declared_size != 0 && declared_size < size {
+            return Err(Error::new("Raw bitmap data size does not match image dimensions"))
+        }
+        Ok(())
     }
 
-    /// read the compression type from header
+    /// read the pixel format from header
     #[inline(always)]
-    fn read_pixel_format(buf: &[u8]) -> Result<PixelFormat, Error> {
+    fn read_pixel_format(header: &DibHeader) -> Result<PixelFormat, Error> {
+        let version = header.version();
+        debug!("dib version = {:?}", version);
+
         // Pixel depth and compression
-        let depth = read_le_u16(buf, consts::PIXEL_DEPTH_POSITION) as u8;
+        let depth = match (version, header.depth) {
+            (_, a @ 1) | (_, a @ 4) | (_, a @ 8) | (_, a @ 24) => a as u8,
+            // OS/2 1.x bitmaps have no 16 and 32 bits pixels
+            (DibVersion::Core, _) => return Err(Error::new("Unsupported pixel depth")),
+            (_, a @ 16) | (_, a @ 32) => a as u8,
+            _ => return Err(Error::new("Unsupported pixel depth")),
+        };
 
-        let compression = match read_le_u32(buf, consts::COMPRESSION_POSITION) {
-            a @ consts::BI_RGB_COMPRESSION |
-            a @ consts::BI_BITFIELDS_COMPRESSION => a,
+        let compression = match (version, header.compression) {
+            // OS/2 uses 3 and 4 for Huffman 1D and RLE24
+            (DibVersion::Os2, consts::BI_BITFIELDS_COMPRESSION) => {
+                return Err(Error::new("Unsupported pixel compression type"))
+            },
+            (_, a @ consts::BI_RGB_COMPRESSION) |
+            (_, a @ consts::BI_RLE8_COMPRESSION) |
+            (_, a @ consts::BI_RLE4_COMPRESSION) |
+            (_, a @ consts::BI_BITFIELDS_COMPRESSION) |
+            (_, a @ consts::BI_ALPHABITFIELDS_COMPRESSION) => a,
             _ => return Err(Error::new("Unsupported pixel compression type")),
         };
 
-        let masks = if depth == 24 && compression == consts::BI_RGB_COMPRESSION {
-            (0xff0000u32, 0xff00u32, 0xffu32, 0u32)
-        } else if compression == consts::BI_BITFIELDS_COMPRESSION {
+        let masks = if compression == consts::BI_RGB_COMPRESSION {
+            match depth {
+                // X1R5G5B5
+                16 => (0x7c00u32, 0x03e0u32, 0x001fu32, 0u32),
+                24 => (0xff0000u32, 0xff00u32, 0xffu32, 0u32),
+                // X8R8G8B8
+                32 => (0xff0000u32, 0xff00u32, 0xffu32, 0u32),
+                // pixels are palette indexes, channels live in palette
+                _ => (0u32, 0u32, 0u32, 0u32),
+            }
+        } else if compression == consts::BI_RLE8_COMPRESSION ||
+                  compression == consts::BI_RLE4_COMPRESSION {
+            if (compression == consts::BI_RLE8_COMPRESSION && depth != 8) ||
+                (compression == consts::BI_RLE4_COMPRESSION && depth != 4) {
+                return Err(Error::new("RLE compression does not match pixel depth"))
+            }
+            (0u32, 0u32, 0u32, 0u32)
+        } else {
+            if depth != 16 && depth != 32 {
+                return Err(Error::new("Bit fields are only allowed on 16 and 32 bits pixels"))
+            }
+            // alpha mask only exists from BITMAPV3INFOHEADER on,
+            // or right after the rgb masks for BI_ALPHABITFIELDS
+            let has_alpha = match version {
+                DibVersion::Info | DibVersion::V2Info => compression == consts::BI_ALPHABITFIELDS_COMPRESSION,
+                _ => true,
+            };
             (
-                read_le_u32(&buf, consts::RED_MASK_POSITION),
-                read_le_u32(&buf, consts::GREEN_MASK_POSITION),
-                read_le_u32(&buf, consts::BLUE_MASK_POSITION),
-                read_le_u32(&buf, consts::ALPHA_MASK_POSITION)
+                header.red_mask,
+                header.green_mask,
+                header.blue_mask,
+                if has_alpha { header.alpha_mask } else { 0u32 }
             )
-        } else {
-            (1u32, 0u32, 0u32, 0u32)
         };
 
+        if masks.0 & masks.1 != 0 || (masks.0 | masks.1) & masks.2 != 0 ||
+            (masks.0 | masks.1 | masks.2) & masks.3 != 0 {
+            return Err(Error::new("Overlapping channel masks"))
+        }
+
+        let depth_mask = if depth < 32 { !(0xffffffffu32 << depth) } else { 0xffffffffu32 };
+        let channels = masks.0 | masks.1 | masks.2 | masks.3;
+        if channels & !depth_mask != 0 {
+            return Err(Error::new("Channel masks exceed pixel depth"))
+        }
+
+        // bits of a pixel out of any channel (e.g. the X of X8R8G8B8),
+        // indexed pixels have none
+        let padding_mask = if depth > consts::MAX_INDEXED_DEPTH { depth_mask & !channels } else { 0u32 };
+
         debug!("red_mask = {:x}", masks.0);
         debug!("green_mask = {:x}", masks.1);
         debug!("blue_mask = {:x}", masks.2);
         debug!("alpha_mask = {:x}", masks.3);
+        debug!("padding_mask = {:x}", padding_mask);
 
 
         Ok( PixelFormat {
@@ -168,11 +542,15 @@ impl Bitmap {
                 green_mask: masks.1,
                 blue_mask: masks.2,
                 alpha_mask: masks.3,
+                padding_mask: padding_mask,
         })
     }
 
 }
 
+/// Iterates over pixels in image coordinates: from the bottom-left
+/// pixel to the top-right one, row by row, no matter whether the
+/// rows are stored bottom-up or top-down
 pub struct Pixels<'a> {
     bitmap: &'a Bitmap,
     cur_row: u32,
@@ -206,7 +584,7 @@ impl<'a> Pixels<'a> {
 
     fn iterate(&mut self) {
         self.cur_column = match self.cur_column {
-            x if x == self.bitmap.width - 1 => {
+            x if x == self.bitmap.width() - 1 => {
                 self.cur_row += 1;
                 0
             },
@@ -218,23 +596,581 @@ impl<'a> Pixels<'a> {
 impl<'a> Iterator for Pixels<'a> {
     type Item = Pixel;
     fn next(&mut self) -> Option<Self::Item> {
-        if (self.cur_row, self.cur_column) == (self.bitmap.height, self.bitmap.width) {
+        if self.cur_row >= self.bitmap.height() {
             None
         } else {
-            let offset = self.bitmap.offset * 8 +
-                            self.cur_row *  self.bitmap.row_length +
-                            self.cur_column * self.bitmap.pixel_format.depth as u32;
-
-            let pixel = Pixel::new(
-                self.bitmap.data.clone(),
-                offset,
-                self.cur_column,
-                self.cur_row,
-                self.bitmap.pixel_format.clone());
-
+            let pixel = self.bitmap.pixel(self.cur_column, self.cur_row);
             self.iterate();
 
             Some(pixel)
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::io::Write;
+
+    use super::Bitmap;
+    use super::BmpFileHeader;
+    use super::palette::Color;
+    use super::DibHeader;
+    use super::Palette;
+    use super::PixelFormat;
+    use super::consts;
+    use super::rle;
+    use super::super::util::write_le_u32;
+    use super::super::secret::BitmapStream;
+    use super::super::secret::EmbedOptions;
+
+    /// Parse the headers of `data`, change them and write them back,
+    /// the DIB header may change size
+    fn edit_headers<F: FnOnce(&mut BmpFileHeader, &mut DibHeader)>(data: &mut Vec<u8>, edit: F) {
+        let mut file_header = BmpFileHeader::parse(data).unwrap();
+        let mut dib_header = DibHeader::parse(&data[consts::FILE_HEADER_SIZE..]).unwrap();
+        let dib_end = consts::FILE_HEADER_SIZE + dib_header.encoded_len();
+        edit(&mut file_header, &mut dib_header);
+        data.splice(consts::FILE_HEADER_SIZE..dib_end, dib_header.to_bytes());
+        data.splice(..consts::FILE_HEADER_SIZE, file_header.to_bytes());
+    }
+
+    fn pixels_offset(data: &[u8]) -> usize {
+        BmpFileHeader::parse(data).unwrap().offset as usize
+    }
+
+    /// Build a 4x2 bitmap with the given DIB header, all the header bytes
+    /// not otherwise set are filled with garbage to check they are kept
+    fn bitmap_data(dib_size: u32, depth: u16, compression: u32, masks: &[u32]) -> Vec<u8> {
+        let row_length = (4 * depth as usize / 8).div_ceil(4) * 4;
+        let extra_masks = if dib_size == consts::INFO_HEADER_SIZE { masks.len() * 4 } else { 0 };
+        let offset = consts::FILE_HEADER_SIZE + dib_size as usize + extra_masks;
+        let mut data: Vec<u8> = (0..offset - extra_masks).map(|i| i as u8 ^ 0x5a).collect();
+
+        data[..2].copy_from_slice(&consts::FILE_SIGNATURE);
+        write_le_u32(&mut data, consts::FILE_HEADER_SIZE, dib_size);
+        edit_headers(&mut data, |file, dib| {
+            file.file_size = (offset + row_length * 2 + 16) as u32;
+            file.offset = offset as u32;
+            dib.width = 4;
+            dib.height = 2;
+            dib.planes = 1;
+            dib.depth = depth;
+            dib.compression = compression;
+            dib.image_size = (row_length * 2) as u32;
+            let mut all_masks = [0u32; 4];
+            all_masks[..masks.len()].copy_from_slice(masks);
+            dib.red_mask = all_masks[0];
+            dib.green_mask = all_masks[1];
+            dib.blue_mask = all_masks[2];
+            dib.alpha_mask = all_masks[3];
+        });
+        // pixels
+        data.extend((0..row_length * 2).map(|i| (i * 37) as u8));
+        // trailing data, e.g. an ICC profile
+        data.extend((0..16).map(|i| 0xf0 | i as u8));
+        data
+    }
+
+    /// Store the rows of a bitmap built by `bitmap_data` top-down
+    fn flip_rows(mut data: Vec<u8>, row_length: usize) -> Vec<u8> {
+        let offset = pixels_offset(&data);
+        edit_headers(&mut data, |_, dib| dib.height = -2);
+        for i in 0..row_length {
+            data.swap(offset + i, offset + row_length + i);
+        }
+        data
+    }
+
+    /// Build a 4x2 indexed bitmap with the given palette
+    fn indexed_bitmap_data(depth: u16, palette: &[Color], pixels: &[u8]) -> Vec<u8> {
+        let mut data = bitmap_data(consts::INFO_HEADER_SIZE, depth, consts::BI_RGB_COMPRESSION, &[]);
+        let offset = pixels_offset(&data);
+        let palette_size = palette.len() * consts::PALETTE_ENTRY_SIZE;
+
+        edit_headers(&mut data, |file, dib| {
+            file.offset = (offset + palette_size) as u32;
+            dib.colors_used = palette.len() as u32;
+        });
+        let entries: Vec<u8> = palette.iter().flat_map(|c| vec![c.blue, c.green, c.red, 0]).collect();
+        data.splice(offset..offset, entries);
+        data[offset + palette_size..offset + palette_size + pixels.len()].copy_from_slice(pixels);
+        data
+    }
+
+    fn gray(level: u8) -> Color {
+        Color { red: level, green: level, blue: level }
+    }
+
+    fn open_stream(data: Vec<u8>) -> BitmapStream {
+        BitmapStream::from_bitmap(Bitmap::try_from(data).unwrap(), &EmbedOptions::default())
+    }
+
+    fn masks(bitmap: &Bitmap) -> (u32, u32, u32, u32) {
+        let format = bitmap.pixel_format();
+        (format.red_mask, format.green_mask, format.blue_mask, format.alpha_mask)
+    }
+
+    #[test]
+    fn info_header_bitfields() {
+        let data = bitmap_data(consts::INFO_HEADER_SIZE, 16, consts::BI_BITFIELDS_COMPRESSION,
+                               &[0xf800, 0x07e0, 0x001f]);
+        let bitmap = Bitmap::try_from(data).unwrap();
+        assert_eq!(masks(&bitmap), (0xf800, 0x07e0, 0x001f, 0));
+    }
+
+    #[test]
+    fn info_header_alphabitfields() {
+        let data = bitmap_data(consts::INFO_HEADER_SIZE, 32, consts::BI_ALPHABITFIELDS_COMPRESSION,
+                               &[0xff0000, 0xff00, 0xff, 0xff000000]);
+        let bitmap = Bitmap::try_from(data).unwrap();
+        assert_eq!(masks(&bitmap), (0xff0000, 0xff00, 0xff, 0xff000000));
+    }
+
+    #[test]
+    fn v2_v3_headers() {
+        let data = bitmap_data(consts::V2_INFO_HEADER_SIZE, 16, consts::BI_BITFIELDS_COMPRESSION,
+                               &[0x7c00, 0x03e0, 0x001f]);
+        assert_eq!(masks(&Bitmap::try_from(data).unwrap()), (0x7c00, 0x03e0, 0x001f, 0));
+
+        let data = bitmap_data(consts::V3_INFO_HEADER_SIZE, 16, consts::BI_BITFIELDS_COMPRESSION,
+                               &[0x0f00, 0x00f0, 0x000f, 0xf000]);
+        assert_eq!(masks(&Bitmap::try_from(data).unwrap()), (0x0f00, 0x00f0, 0x000f, 0xf000));
+    }
+
+    #[test]
+    fn v4_v5_headers() {
+        let data = bitmap_data(consts::V4_HEADER_SIZE, 32, consts::BI_BITFIELDS_COMPRESSION,
+                               &[0xff0000, 0xff00, 0xff, 0xff000000]);
+        assert_eq!(masks(&Bitmap::try_from(data).unwrap()), (0xff0000, 0xff00, 0xff, 0xff000000));
+
+        // masks in header are ignored without BI_BITFIELDS
+        let data = bitmap_data(consts::V5_HEADER_SIZE, 24, consts::BI_RGB_COMPRESSION,
+                               &[0xff, 0xff00, 0xff0000, 0]);
+        assert_eq!(masks(&Bitmap::try_from(data).unwrap()), (0xff0000, 0xff00, 0xff, 0));
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let data = bitmap_data(consts::V5_HEADER_SIZE, 24, consts::BI_BITFIELDS_COMPRESSION,
+                               &[0xff0000, 0xff00, 0xff, 0]);
+        assert!(Bitmap::try_from(data).is_err());
+
+        let data = bitmap_data(consts::V5_HEADER_SIZE, 32, consts::BI_BITFIELDS_COMPRESSION,
+                               &[0xff0000, 0xffff00, 0xff, 0]);
+        assert!(Bitmap::try_from(data).is_err());
+
+        let mut data = bitmap_data(consts::INFO_HEADER_SIZE, 24, consts::BI_RGB_COMPRESSION, &[]);
+        write_le_u32(&mut data, consts::FILE_HEADER_SIZE, 44);
+        assert!(Bitmap::try_from(data).is_err());
+    }
+
+    #[test]
+    fn row_length_from_dimensions() {
+        // BI_RGB files may leave the raw data size empty
+        let mut data = bitmap_data(consts::INFO_HEADER_SIZE, 24, consts::BI_RGB_COMPRESSION, &[]);
+        edit_headers(&mut data, |_, dib| dib.image_size = 0);
+        let hidden = {
+            let mut stream = open_stream(data.clone());
+            assert_eq!(stream.write(&[0xff, 0xff, 0xff]).unwrap(), 3);
+            stream.flush().unwrap();
+            Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap()
+        };
+        let offset = pixels_offset(&data);
+        for i in 0..24 {
+            assert_eq!(hidden[offset + i], data[offset + i] | 1);
+        }
+
+        // padded raw data size
+        edit_headers(&mut data, |_, dib| dib.image_size = 32);
+        assert!(Bitmap::try_from(data.clone()).is_ok());
+
+        edit_headers(&mut data, |_, dib| dib.image_size = 12);
+        assert!(Bitmap::try_from(data.clone()).is_err());
+
+        edit_headers(&mut data, |_, dib| dib.image_size = 24);
+        let len = data.len();
+        data.truncate(len - 17);
+        assert!(Bitmap::try_from(data.clone()).is_err());
+
+        let mut data = bitmap_data(consts::INFO_HEADER_SIZE, 24, consts::BI_RGB_COMPRESSION, &[]);
+        edit_headers(&mut data, |file, _| file.offset = 14);
+        assert!(Bitmap::try_from(data).is_err());
+    }
+
+    #[test]
+    fn hide_keeps_everything_but_pixels() {
+        let data = bitmap_data(consts::V5_HEADER_SIZE, 32, consts::BI_BITFIELDS_COMPRESSION,
+                               &[0xff0000, 0xff00, 0xff, 0xff000000]);
+        let offset = pixels_offset(&data);
+        let pixels_end = offset + 4 * 4 * 2;
+
+        let mut stream = open_stream(data.clone());
+        assert_eq!(stream.write(&[0xa5, 0x3c, 0x0f, 0xf0]).unwrap(), 4);
+        stream.flush().unwrap();
+
+        let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+        assert_eq!(hidden.len(), data.len());
+        assert_eq!(&hidden[..offset], &data[..offset]);
+        assert_eq!(&hidden[pixels_end..], &data[pixels_end..]);
+
+        // alpha is the highest byte, so the first pixel byte holds blue
+        assert_eq!(hidden[offset] & 1, 1);
+
+        let mut stream = open_stream(hidden);
+        let mut message = [0u8; 4];
+        assert_eq!(stream.read(&mut message).unwrap(), 4);
+        assert_eq!(message, [0xa5, 0x3c, 0x0f, 0xf0]);
+    }
+
+    #[test]
+    fn top_down_uses_image_coordinates() {
+        let bottom_up = bitmap_data(consts::INFO_HEADER_SIZE, 24, consts::BI_RGB_COMPRESSION, &[]);
+        let top_down = flip_rows(bottom_up.clone(), 12);
+        assert!(Bitmap::try_from(top_down.clone()).unwrap().is_top_down());
+
+        let mut hidden = Vec::new();
+        for data in [bottom_up, top_down] {
+            let mut stream = open_stream(data);
+            assert_eq!(stream.write(&[0xa5, 0x3c, 0x0f]).unwrap(), 3);
+            stream.flush().unwrap();
+            hidden.push(Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap());
+        }
+
+        assert_eq!(flip_rows(hidden[0].clone(), 12), hidden[1]);
+
+        let mut stream = open_stream(hidden.pop().unwrap());
+        let mut message = [0u8; 3];
+        assert_eq!(stream.read(&mut message).unwrap(), 3);
+        assert_eq!(message, [0xa5, 0x3c, 0x0f]);
+    }
+
+    #[test]
+    fn indexed_palette() {
+        let palette = [gray(0), gray(255)];
+        let bitmap = Bitmap::try_from(indexed_bitmap_data(1, &palette, &[0x50, 0, 0, 0, 0xa0])).unwrap();
+        assert_eq!(bitmap.palette().colors(), &palette);
+        assert_eq!(masks(&bitmap), (0, 0, 0, 0));
+
+        let mut data = indexed_bitmap_data(4, &palette, &[0x10; 8]);
+        edit_headers(&mut data, |_, dib| dib.colors_used = 17);
+        assert!(Bitmap::try_from(data).is_err());
+    }
+
+    #[test]
+    fn indexed_hide_moves_to_neighbour_colors() {
+        // luminance order: 2, 0, 3, 1, 4
+        let palette = [gray(40), gray(200), gray(10), gray(90), gray(250)];
+        let pixels = [2, 0, 3, 1, 4, 4, 0, 0];
+        let data = indexed_bitmap_data(8, &palette, &pixels);
+        let offset = pixels_offset(&data);
+
+        let mut stream = open_stream(data);
+        assert_eq!(stream.write(&[0x5a]).unwrap(), 1);
+        stream.flush().unwrap();
+        let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+
+        // 0 1 0 1 | 1 0 1 0
+        assert_eq!(&hidden[offset..offset + 4], &[2, 0, 3, 1]);
+        assert_eq!(&hidden[offset + 4..offset + 8], &[1, 4, 0, 2]);
+
+        let mut stream = open_stream(hidden);
+        let mut message = [0u8];
+        assert_eq!(stream.read(&mut message).unwrap(), 1);
+        assert_eq!(message, [0x5a]);
+    }
+
+    #[test]
+    fn rle_hide() {
+        let palette = [gray(40), gray(200), gray(10), gray(90), gray(250)];
+        let pixels = [2, 2, 2, 2, 4, 4, 0, 0];
+        let mut data = indexed_bitmap_data(8, &palette, &pixels);
+        let offset = pixels_offset(&data);
+
+        // compress pixels, keeping trailing data
+        let trailer = data.split_off(offset + pixels.len());
+        data.truncate(offset);
+        let compressed = rle::encode(&pixels, 4, 2, 8, 4);
+        edit_headers(&mut data, |_, dib| {
+            dib.compression = consts::BI_RLE8_COMPRESSION;
+            dib.image_size = compressed.len() as u32;
+        });
+        data.extend(compressed);
+        data.extend(trailer.iter());
+
+        for &uncompressed in &[false, true] {
+            let mut bitmap = Bitmap::try_from(data.clone()).unwrap();
+            bitmap.set_uncompressed_output(uncompressed);
+            let mut stream = BitmapStream::from_bitmap(bitmap, &EmbedOptions::default());
+            assert_eq!(stream.write(&[0x5a]).unwrap(), 1);
+            stream.flush().unwrap();
+            let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+
+            assert_eq!(&hidden[hidden.len() - trailer.len()..], &trailer[..]);
+            assert_eq!(BmpFileHeader::parse(&hidden).unwrap().file_size as usize, hidden.len());
+            let dib = DibHeader::parse(&hidden[consts::FILE_HEADER_SIZE..]).unwrap();
+            let size = dib.image_size as usize;
+            let compression = dib.compression;
+            if uncompressed {
+                assert_eq!(compression, consts::BI_RGB_COMPRESSION);
+                assert_eq!(&hidden[offset..offset + size], &[2, 0, 2, 0, 1, 4, 0, 2]);
+            } else {
+                assert_eq!(compression, consts::BI_RLE8_COMPRESSION);
+                assert_eq!(rle::decode(&hidden[offset..offset + size], 4, 2, 8, 4).unwrap(),
+                           vec![2, 0, 2, 0, 1, 4, 0, 2]);
+            }
+
+            let mut stream = open_stream(hidden);
+            let mut message = [0u8];
+            assert_eq!(stream.read(&mut message).unwrap(), 1);
+            assert_eq!(message, [0x5a]);
+        }
+    }
+
+    #[test]
+    fn default_rgb_layouts() {
+        let bitmap = Bitmap::try_from(bitmap_data(consts::INFO_HEADER_SIZE, 16, consts::BI_RGB_COMPRESSION, &[])).unwrap();
+        assert_eq!(masks(&bitmap), (0x7c00, 0x03e0, 0x001f, 0));
+        assert_eq!(bitmap.pixel_format().padding_mask, 0x8000);
+
+        let bitmap = Bitmap::try_from(bitmap_data(consts::INFO_HEADER_SIZE, 32, consts::BI_RGB_COMPRESSION, &[])).unwrap();
+        assert_eq!(masks(&bitmap), (0xff0000, 0xff00, 0xff, 0));
+        assert_eq!(bitmap.pixel_format().padding_mask, 0xff000000);
+
+        let bitmap = Bitmap::try_from(bitmap_data(consts::INFO_HEADER_SIZE, 24, consts::BI_RGB_COMPRESSION, &[])).unwrap();
+        assert_eq!(bitmap.pixel_format().padding_mask, 0);
+    }
+
+    #[test]
+    fn padding_carrier() {
+        let data = bitmap_data(consts::INFO_HEADER_SIZE, 32, consts::BI_RGB_COMPRESSION, &[]);
+        let message = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc, 0xba];
+
+        assert_eq!(open_stream(data.clone()).write(&message).unwrap(), 3);
+
+        let options = EmbedOptions { padding: true, ..EmbedOptions::default() };
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(data).unwrap(), &options);
+        assert_eq!(stream.write(&message).unwrap(), 11);
+        stream.flush().unwrap();
+        let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+
+        // first pixel: 3 channel bits and 8 padding bits
+        let offset = pixels_offset(&hidden);
+        assert_eq!(hidden[offset + 3], 0x90);
+
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(hidden).unwrap(), &options);
+        let mut read = [0u8; 11];
+        assert_eq!(stream.read(&mut read).unwrap(), 11);
+        assert_eq!(read, message);
+    }
+
+    /// Build a 4x2 OS/2 1.x bitmap, with a 3 colors palette when indexed
+    fn core_bitmap_data(depth: u16) -> Vec<u8> {
+        let palette: Vec<u8> = if depth == 24 { vec![] } else { vec![0, 0, 0, 0x80, 0x80, 0x80, 0xff, 0xff, 0xff] };
+        let row_length = (4 * depth as usize / 8).div_ceil(4) * 4;
+        let offset = consts::FILE_HEADER_SIZE + consts::CORE_HEADER_SIZE as usize + palette.len();
+
+        let mut data = BmpFileHeader {
+            file_size: (offset + row_length * 2) as u32,
+            reserved1: 0x5a5a,
+            reserved2: 0xa5a5,
+            offset: offset as u32,
+        }.to_bytes();
+        data.extend(DibHeader {
+            size: consts::CORE_HEADER_SIZE,
+            width: 4,
+            height: 2,
+            planes: 1,
+            depth: depth,
+            ..DibHeader::default()
+        }.to_bytes());
+        data.extend(palette);
+        data.extend((0..row_length * 2).map(|i| (i % 3) as u8));
+        data
+    }
+
+    #[test]
+    fn os2_core_header() {
+        let bitmap = Bitmap::try_from(core_bitmap_data(24)).unwrap();
+        assert_eq!(bitmap.height(), 2);
+        assert_eq!(masks(&bitmap), (0xff0000, 0xff00, 0xff, 0));
+
+        let data = core_bitmap_data(8);
+        let bitmap = Bitmap::try_from(data.clone()).unwrap();
+        assert_eq!(bitmap.palette().colors(), &[gray(0), gray(0x80), gray(0xff)]);
+
+        let offset = data.len() - 8;
+        let mut stream = open_stream(data.clone());
+        assert_eq!(stream.write(&[0x5a]).unwrap(), 1);
+        stream.flush().unwrap();
+        let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+        assert_eq!(&hidden[..offset], &data[..offset]);
+        assert_eq!(&hidden[offset..], &[0, 1, 2, 1, 1, 2, 1, 0]);
+    }
+
+    #[test]
+    fn os2_v2_header() {
+        let palette = [gray(0), gray(255)];
+        let mut data = indexed_bitmap_data(8, &palette, &[0, 1, 0, 1, 1, 0, 1, 0]);
+        // grow the header to 64 bytes
+        edit_headers(&mut data, |file, dib| {
+            file.offset += 24;
+            dib.size = consts::OS2_V2_HEADER_SIZE;
+            dib.os2.identifier = 0x5a5a5a5a;
+        });
+
+        let bitmap = Bitmap::try_from(data.clone()).unwrap();
+        assert_eq!(bitmap.palette().colors(), &palette);
+
+        let mut message = [0u8];
+        assert_eq!(open_stream(data.clone()).read(&mut message).unwrap(), 1);
+        assert_eq!(message, [0x5a]);
+
+        // OS/2 Huffman 1D compression
+        edit_headers(&mut data, |_, dib| dib.compression = 3);
+        assert!(Bitmap::try_from(data).is_err());
+
+        // truncated header, compression and following fields are zeroed
+        let mut data = core_bitmap_data(24);
+        edit_headers(&mut data, |file, dib| {
+            file.offset += 4;
+            dib.size = consts::OS2_V2_SHORT_HEADER_SIZE;
+        });
+        let bitmap = Bitmap::try_from(data).unwrap();
+        assert_eq!(masks(&bitmap), (0xff0000, 0xff00, 0xff, 0));
+    }
+
+    #[test]
+    fn headers_round_trip() {
+        let mut files = vec![
+            bitmap_data(consts::V5_HEADER_SIZE, 32, consts::BI_BITFIELDS_COMPRESSION,
+                        &[0xff0000, 0xff00, 0xff, 0xff000000]),
+            bitmap_data(consts::INFO_HEADER_SIZE, 16, consts::BI_BITFIELDS_COMPRESSION,
+                        &[0xf800, 0x07e0, 0x001f]),
+            indexed_bitmap_data(4, &[gray(0), gray(90), gray(255)], &[0x12, 0x01, 0, 0, 0x21, 0x10]),
+            core_bitmap_data(8),
+        ];
+        let mut os2 = indexed_bitmap_data(8, &[gray(0), gray(255)], &[0, 1, 0, 1, 1, 0, 1, 0]);
+        edit_headers(&mut os2, |file, dib| {
+            file.offset += 24;
+            dib.size = consts::OS2_V2_HEADER_SIZE;
+            dib.os2.rendering = 0xa5a5;
+        });
+        files.push(os2);
+
+        for data in files {
+            let offset = pixels_offset(&data);
+            let bitmap = Bitmap::try_from(data.clone()).unwrap();
+
+            // headers and palette serialize to the very same bytes
+            let mut headers = bitmap.file_header.to_bytes();
+            headers.extend(bitmap.dib_header.to_bytes());
+            headers.extend(bitmap.palette().to_bytes());
+            assert_eq!(&headers[..], &data[..headers.len()]);
+            assert_eq!(Palette::parse(&bitmap.palette().to_bytes(), bitmap.palette().len(),
+                                      bitmap.palette().entry_size()).unwrap(), *bitmap.palette());
+
+            // hiding only touches the pixel array
+            let pixels_end = offset + (bitmap.row_length / 8 * bitmap.height()) as usize;
+            let mut stream = BitmapStream::from_bitmap(bitmap, &EmbedOptions::default());
+            stream.write_all(&[0xa5]).unwrap();
+            stream.flush().unwrap();
+            let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+            assert_eq!(hidden.len(), data.len());
+            assert_eq!(&hidden[..offset], &data[..offset]);
+            assert_eq!(&hidden[pixels_end..], &data[pixels_end..]);
+        }
+    }
+
+    fn format(depth: u8, red_mask: u32, green_mask: u32, blue_mask: u32, alpha_mask: u32) -> PixelFormat {
+        PixelFormat {
+            depth: depth,
+            red_mask: red_mask,
+            green_mask: green_mask,
+            blue_mask: blue_mask,
+            alpha_mask: alpha_mask,
+            padding_mask: 0,
+        }
+    }
+
+    #[test]
+    fn new_bitmaps() {
+        let palette = [gray(0), gray(90), gray(255)];
+        let formats = [
+            (format(1, 0, 0, 0, 0), &palette[..2], 1, consts::INFO_HEADER_SIZE),
+            (format(4, 0, 0, 0, 0), &palette[..], 2, consts::INFO_HEADER_SIZE),
+            (format(8, 0, 0, 0, 0), &palette[..], 2, consts::INFO_HEADER_SIZE),
+            (format(16, 0x7c00, 0x03e0, 0x001f, 0), &[][..], 0x1234, consts::INFO_HEADER_SIZE),
+            (format(16, 0xf800, 0x07e0, 0x001f, 0), &[][..], 0x1234, consts::INFO_HEADER_SIZE),
+            (format(16, 0x0f00, 0x00f0, 0x000f, 0xf000), &[][..], 0x1234, consts::V4_HEADER_SIZE),
+            (format(24, 0xff0000, 0xff00, 0xff, 0), &[][..], 0x123456, consts::INFO_HEADER_SIZE),
+            (format(32, 0xff0000, 0xff00, 0xff, 0), &[][..], 0x12345678, consts::INFO_HEADER_SIZE),
+            (format(32, 0xff0000, 0xff00, 0xff, 0xff000000), &[][..], 0x12345678, consts::V4_HEADER_SIZE),
+        ];
+
+        for &(ref format, palette, value, dib_size) in formats.iter() {
+            let bitmap = Bitmap::filled(5, 3, format, palette, value).unwrap();
+            let data = Bitmap::try_unwrap_data(bitmap).ok().unwrap();
+
+            let mut bitmap = Bitmap::try_from(data.clone()).unwrap();
+            assert_eq!((bitmap.width(), bitmap.height()), (5, 3));
+            assert_eq!(bitmap.dib_header.size, dib_size);
+            assert_eq!(bitmap.file_header.file_size as usize, data.len());
+            assert_eq!(bitmap.palette().colors(), palette);
+            assert_eq!(masks(&bitmap), (format.red_mask, format.green_mask, format.blue_mask, format.alpha_mask));
+            let values: Vec<u32> = bitmap.pixels_from(&None).map(|mut pixel| pixel.value().unwrap()).collect();
+            assert_eq!(values, vec![value; 15]);
+
+            let mut stream = open_stream(Bitmap::try_unwrap_data(Bitmap::new(5, 3, format, palette).unwrap()).ok().unwrap());
+            assert_eq!(stream.write(&[0xa5]).unwrap(), 1);
+            stream.flush().unwrap();
+            let mut stream = open_stream(Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap());
+            let mut message = [0u8];
+            assert_eq!(stream.read(&mut message).unwrap(), 1);
+            assert_eq!(message, [0xa5]);
+        }
+
+        // 1 bit rows are padded, pixels go from the high bits
+        let data = Bitmap::try_unwrap_data(Bitmap::filled(9, 1, &format(1, 0, 0, 0, 0), &palette[..2], 1).unwrap()).ok().unwrap();
+        assert_eq!(&data[data.len() - 4..], &[0xff, 0x80, 0, 0]);
+    }
+
+    #[test]
+    fn converted_bitmaps() {
+        let palette = [gray(0), gray(90), Color { red: 10, green: 20, blue: 30 }];
+        let converted = |mut bitmap: Bitmap, depth: u8| {
+            let bitmap = bitmap.convert(depth).unwrap();
+            let format = bitmap.pixel_format().clone();
+            let mut bitmap = Bitmap::try_from(Bitmap::try_unwrap_data(bitmap).ok().unwrap()).unwrap();
+            let values: Vec<u32> = bitmap.pixels_from(&None).map(|mut pixel| pixel.value().unwrap()).collect();
+            (format.depth, format.alpha_mask, values)
+        };
+
+        let mut indexed = Bitmap::filled(2, 2, &format(4, 0, 0, 0, 0), &palette, 1).unwrap();
+        indexed.pixels_from(&None).nth(1).unwrap().set_value(2).unwrap();
+        assert_eq!(converted(indexed, 24), (24, 0, vec![0x5a5a5a, 0x0a141e, 0x5a5a5a, 0x5a5a5a]));
+
+        // 5 bits channels are scaled up, alpha is kept at 32 bits only
+        let rgb16 = Bitmap::filled(1, 1, &format(16, 0x0f00, 0x00f0, 0x000f, 0xf000), &[], 0x8f10).unwrap();
+        assert_eq!(converted(rgb16, 32), (32, 0xff000000, vec![0x88ff1100]));
+        let rgb16 = Bitmap::filled(1, 1, &format(16, 0x7c00, 0x03e0, 0x001f, 0), &[], 0x7fff).unwrap();
+        assert_eq!(converted(rgb16, 32), (32, 0, vec![0xffffff]));
+
+        let mut rgb24 = Bitmap::filled(1, 1, &format(24, 0xff0000, 0xff00, 0xff, 0), &[], 0x123456).unwrap();
+        assert!(rgb24.convert(16).is_err());
+    }
+
+    #[test]
+    fn invalid_new_bitmaps() {
+        let palette = [gray(0), gray(255), gray(90)];
+        assert!(Bitmap::new(0, 3, &format(8, 0, 0, 0, 0), &palette).is_err());
+        assert!(Bitmap::new(3, 0, &format(8, 0, 0, 0, 0), &palette).is_err());
+        assert!(Bitmap::new(3, 3, &format(1, 0, 0, 0, 0), &palette).is_err());
+        assert!(Bitmap::new(3, 3, &format(8, 0, 0, 0, 0), &[]).is_err());
+        assert!(Bitmap::new(3, 3, &format(24, 0, 0, 0, 0), &palette).is_err());
+        assert!(Bitmap::new(3, 3, &format(24, 0xff, 0xff00, 0xff0000, 0), &[]).is_err());
+        assert!(Bitmap::new(3, 3, &format(32, 0xff, 0xff00, 0xff0100, 0), &[]).is_err());
+        assert!(Bitmap::new(3, 3, &format(12, 0xf00, 0xf0, 0xf, 0), &[]).is_err());
+        assert!(Bitmap::new(0x10000, 0x10000, &format(32, 0xff0000, 0xff00, 0xff, 0), &[]).is_err());
+    }
+}
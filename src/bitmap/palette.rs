@@ -0,0 +1,159 @@
+use std::vec::Vec;
+
+use super::consts;
+use super::super::error::Error;
+
+/// A color table entry
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    /// Perceived brightness (ITU-R BT.601 weights, scaled by 1000)
+    #[inline(always)]
+    pub fn luminance(&self) -> u32 {
+        299 * self.red as u32 + 587 * self.green as u32 + 114 * self.blue as u32
+    }
+}
+
+/// Color table of an indexed image
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Palette {
+    colors: Vec<Color>,
+    /// last byte of each RGBQUAD entry, `None` for RGBTRIPLE entries
+    reserved: Option<Vec<u8>>,
+}
+
+impl Palette {
+
+    /// Palette of RGBQUAD entries
+    pub fn new(colors: &[Color]) -> Palette {
+        Palette {
+            colors: colors.to_vec(),
+            reserved: Some(vec![0; colors.len()]),
+        }
+    }
+
+    /// Parse `count` entries of `entry_size` bytes (4 or 3 for
+    /// OS/2 1.x bitmaps) from start of `data`
+    pub fn parse(data: &[u8], count: usize, entry_size: usize) -> Result<Palette, Error> {
+        let end = count * entry_size;
+        if data.len() < end {
+            return Err(Error::new("Truncated palette"))
+        }
+
+        // entries are stored as blue, green, red (and reserved)
+        let entries = data[..end].chunks(entry_size);
+        Ok(Palette {
+            colors: entries.clone().map(|entry| Color {
+                red: entry[2],
+                green: entry[1],
+                blue: entry[0],
+            }).collect(),
+            reserved: if entry_size == consts::PALETTE_ENTRY_SIZE {
+                Some(entries.map(|entry| entry[3]).collect())
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.colors.len() * self.entry_size());
+        for (i, color) in self.colors.iter().enumerate() {
+            data.extend_from_slice(&[color.blue, color.green, color.red]);
+            if let Some(ref reserved) = self.reserved {
+                data.push(reserved[i]);
+            }
+        }
+        data
+    }
+
+    #[inline(always)]
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    #[inline(always)]
+    pub fn entry_size(&self) -> usize {
+        match self.reserved {
+            Some(_) => consts::PALETTE_ENTRY_SIZE,
+            None => consts::CORE_PALETTE_ENTRY_SIZE,
+        }
+    }
+
+    /// Size of color table in file
+    #[inline(always)]
+    pub fn encoded_len(&self) -> usize {
+        self.colors.len() * self.entry_size()
+    }
+}
+
+/// Palette indexes sorted by luminance, as done by EzStego.
+///
+/// A secret bit is the parity of the position of a pixel index in
+/// this order, so changing a bit moves the pixel to a neighbour color
+/// in luminance instead of to an unrelated palette entry.
+pub struct LuminanceOrder {
+    /// palette indexes sorted by luminance
+    sorted: Vec<u32>,
+    /// position of each palette index in `sorted`
+    position: Vec<u32>,
+}
+
+impl LuminanceOrder {
+
+    pub fn from_colors(colors: &[Color]) -> LuminanceOrder {
+        let mut sorted: Vec<u32> = (0..colors.len() as u32).collect();
+        // stable sort, so ties keep palette order on both sides
+        sorted.sort_by_key(|i| colors[*i as usize].luminance());
+
+        let mut position = vec![0u32; colors.len()];
+        for (pos, index) in sorted.iter().enumerate() {
+            position[*index as usize] = pos as u32;
+        }
+
+        LuminanceOrder {
+            sorted: sorted,
+            position: position,
+        }
+    }
+
+    /// Bit carried by palette `index`, `None` if index is out of palette
+    #[inline(always)]
+    pub fn parity(&self, index: u32) -> Option<bool> {
+        self.position.get(index as usize).map(|pos| pos & 1 == 1)
+    }
+
+    /// Returns `index` itself if it already carries `bit`, otherwise its
+    /// neighbour in luminance order
+    pub fn with_parity(&self, index: u32, bit: bool) -> Option<u32> {
+        let pos = match self.position.get(index as usize) {
+            Some(pos) => *pos,
+            None => return None,
+        };
+        if (pos & 1 == 1) == bit {
+            return Some(index)
+        }
+        if self.sorted.len() < 2 {
+            return None
+        }
+        // the last color of an odd sized palette has no pair,
+        // but its previous one has the other parity as well
+        let pair = if (pos ^ 1) < self.sorted.len() as u32 { pos ^ 1 } else { pos - 1 };
+        Some(self.sorted[pair as usize])
+    }
+}
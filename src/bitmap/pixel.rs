@@ -22,6 +22,8 @@ pub struct PixelFormat {
     pub green_mask: u32,
     pub blue_mask: u32,
     pub alpha_mask: u32,
+    // unused bits of pixel
+    pub padding_mask: u32,
 }
 
 pub struct Pixel {
@@ -31,7 +33,7 @@ pub struct Pixel {
     offset: u32,
     /// Column
     column: u32,
-    /// Row
+    /// Row, counted from the bottom of the image
     row: u32,
     /// Processed data
     value: u32,
@@ -73,7 +75,8 @@ impl Pixel {
             // seek offset (in bits)
             try!(buf.seek(BitSeekFrom::Start(self.offset as u64)));
 
-            self.value = try!(buf.read(self.pixel_format.depth));
+            let raw = try!(buf.read(self.pixel_format.depth));
+            self.value = swap_pixel_bytes(raw, self.pixel_format.depth);
         }
 
         Ok(self.value)
@@ -81,12 +84,16 @@ impl Pixel {
 
     /// set data of a pixel
     pub fn set_value(&mut self, value: u32) -> Result<(), Error> {
-        self.value = value & !(0xffffffff << self.pixel_format.depth);
+        self.value = if self.pixel_format.depth < 32 {
+            value & !(0xffffffff << self.pixel_format.depth)
+        } else {
+            value
+        };
         self.value_already_read = true;
 
         let mut buf = self.bitmap_data.borrow_mut();
         try!(buf.seek(BitSeekFrom::Start(self.offset as u64)));
-        try!(buf.write(self.value, self.pixel_format.depth));
+        try!(buf.write(swap_pixel_bytes(self.value, self.pixel_format.depth), self.pixel_format.depth));
         Ok(())
     }
 
@@ -140,6 +147,31 @@ impl Pixel {
 //    }
 }
 
+/// Pixels wider than a byte are stored in little endian, but `BitBuf`
+/// reads big endian values, so the bytes are swapped to let the channel
+/// masks be applied as they are written in header.
+///
+/// Swapping is its own inverse, so it is used for reading and writing
+#[inline(always)]
+fn swap_pixel_bytes(value: u32, depth: u8) -> u32 {
+    match depth {
+        16 => (value as u16).swap_bytes() as u32,
+        24 => value.swap_bytes() >> 8,
+        32 => value.swap_bytes(),
+        _ => value,
+    }
+}
+
+/// Value of the `mask` bits of `value`, scaled from 0 to 255
+pub fn channel_level(value: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0
+    }
+    let shift = mask.trailing_zeros();
+    let highest = (mask >> shift) as u64;
+    (((value & mask) >> shift) as u64 * 255 / highest) as u8
+}
+
 impl Clone for Pixel {
     fn clone(&self) -> Self {
         Pixel {
@@ -162,6 +194,20 @@ impl Clone for PixelFormat {
             green_mask: self.green_mask,
             blue_mask: self.blue_mask,
             alpha_mask: self.alpha_mask,
+            padding_mask: self.padding_mask,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::channel_level;
+
+    #[test]
+    fn channel_levels() {
+        assert_eq!(channel_level(0xff00, 0xfe00), 255);
+        assert_eq!(channel_level(0x01ff, 0xfe00), 0);
+        assert_eq!(channel_level(0x10, 0x1c), 145);
+        assert_eq!(channel_level(0x1234, 0), 0);
+    }
+}
@@ -0,0 +1,260 @@
+// Run length encoding of 4 and 8 bits indexed pixel arrays
+// (BI_RLE4 and BI_RLE8 compressions)
+use std::vec::Vec;
+
+use super::super::error::Error;
+
+/// escape byte of an encoded pair
+const ESCAPE: u8 = 0;
+/// escape codes
+const END_OF_LINE: u8 = 0;
+const END_OF_BITMAP: u8 = 1;
+const DELTA: u8 = 2;
+
+/// Largest expanded pixel array, bit offsets of pixels are kept in u32
+const MAX_SIZE: usize = u32::MAX as usize / 8;
+
+/// How a RLE compressed pixel array was stored in file,
+/// pixels are kept expanded while hiding and compressed back on output
+pub struct Rle {
+    /// BI_RLE8 or BI_RLE4
+    pub compression: u32,
+    /// size of compressed pixel array in source file
+    pub size: u32,
+    /// data following the pixel array in source file
+    pub trailer: Vec<u8>,
+    /// write pixels back uncompressed (BI_RGB)
+    pub expand: bool,
+}
+
+/// Expand a RLE compressed pixel array of `rows` rows of `width`
+/// pixels of `depth` (4 or 8) bits into an uncompressed bottom-up
+/// pixel array whose rows have `row_length` bytes.
+///
+/// Pixels skipped by delta escapes are left zeroed
+pub fn decode(data: &[u8], width: u32, rows: u32, depth: u8, row_length: usize) -> Result<Vec<u8>, Error> {
+    // dimensions come from the header, check them before allocating
+    let size = match row_length.checked_mul(rows as usize) {
+        Some(size) if size <= MAX_SIZE => size,
+        _ => return Err(Error::new("Image too large")),
+    };
+    let mut pixels = vec![0u8; size];
+    let mut x = 0u32;
+    let mut y = 0u32;
+    let mut pos = 0usize;
+
+    macro_rules! next_byte {
+        () => ({
+            if pos >= data.len() {
+                return Err(Error::new("Truncated RLE data"))
+            }
+            pos += 1;
+            data[pos - 1]
+        })
+    }
+
+    macro_rules! put_pixel {
+        ($value:expr) => ({
+            if x >= width || y >= rows {
+                return Err(Error::new("RLE data out of image bounds"))
+            }
+            set_pixel(&mut pixels, row_length * y as usize, x, depth, $value);
+            x += 1;
+        })
+    }
+
+    while pos < data.len() {
+        let count = next_byte!();
+        let value = next_byte!();
+
+        if count != ESCAPE {
+            // encoded mode: `count` pixels of `value` (alternating nibbles for RLE4)
+            for i in 0..count {
+                put_pixel!(nibble_or_byte(value, depth, i as usize));
+            }
+            continue
+        }
+
+        match value {
+            END_OF_LINE => {
+                x = 0;
+                y += 1;
+            },
+            END_OF_BITMAP => break,
+            DELTA => {
+                x += next_byte!() as u32;
+                y += next_byte!() as u32;
+            },
+            count => {
+                // absolute mode: `count` literal pixels padded to 16 bits
+                let bytes = (count as usize * depth as usize).div_ceil(8);
+                if pos + bytes > data.len() {
+                    return Err(Error::new("Truncated RLE data"))
+                }
+                for i in 0..count as usize {
+                    let byte = data[pos + i * depth as usize / 8];
+                    put_pixel!(nibble_or_byte(byte, depth, i));
+                }
+                pos += bytes + bytes % 2;
+            }
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Compress a bottom-up pixel array of `rows` rows of `width` pixels
+/// of `depth` (4 or 8) bits, whose rows have `row_length` bytes
+pub fn encode(pixels: &[u8], width: u32, rows: u32, depth: u8, row_length: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    let width = width as usize;
+
+    for y in 0..rows as usize {
+        let row: Vec<u8> = (0..width)
+            .map(|x| get_pixel(pixels, row_length * y, x as u32, depth))
+            .collect();
+
+        let mut x = 0usize;
+        let mut literal_start = 0usize;
+        while x < width {
+            let run = row[x..].iter().take(255).take_while(|p| **p == row[x]).count();
+            // runs shorter than 3 pixels are cheaper inside literals
+            if run < 3 {
+                x += 1;
+                if x - literal_start == 255 {
+                    write_literal(&mut data, &row[literal_start..x], depth);
+                    literal_start = x;
+                }
+                continue
+            }
+            write_literal(&mut data, &row[literal_start..x], depth);
+            data.push(run as u8);
+            data.push(if depth == 4 { row[x] << 4 | row[x] } else { row[x] });
+            x += run;
+            literal_start = x;
+        }
+        write_literal(&mut data, &row[literal_start..], depth);
+
+        data.push(ESCAPE);
+        data.push(if y + 1 == rows as usize { END_OF_BITMAP } else { END_OF_LINE });
+    }
+
+    data
+}
+
+/// Write `pixels` in absolute mode, which needs at least 3 pixels,
+/// shorter sequences are written as runs of a single pixel
+fn write_literal(data: &mut Vec<u8>, pixels: &[u8], depth: u8) {
+    if pixels.len() < 3 {
+        for pixel in pixels {
+            data.push(1);
+            data.push(if depth == 4 { pixel << 4 } else { *pixel });
+        }
+        return
+    }
+
+    data.push(ESCAPE);
+    data.push(pixels.len() as u8);
+    let start = data.len();
+    if depth == 4 {
+        for pair in pixels.chunks(2) {
+            data.push(pair[0] << 4 | if pair.len() > 1 { pair[1] } else { 0 });
+        }
+    } else {
+        data.extend_from_slice(pixels);
+    }
+    if (data.len() - start) % 2 != 0 {
+        data.push(0);
+    }
+}
+
+/// pixel `i` of an encoded pair or of a literal byte
+#[inline(always)]
+fn nibble_or_byte(value: u8, depth: u8, i: usize) -> u8 {
+    if depth == 4 {
+        if i % 2 == 0 { value >> 4 } else { value & 0xf }
+    } else {
+        value
+    }
+}
+
+#[inline(always)]
+fn set_pixel(pixels: &mut [u8], row_start: usize, x: u32, depth: u8, value: u8) {
+    if depth == 4 {
+        let byte = &mut pixels[row_start + x as usize / 2];
+        if x % 2 == 0 {
+            *byte = *byte & 0x0f | value << 4;
+        } else {
+            *byte = *byte & 0xf0 | value & 0x0f;
+        }
+    } else {
+        pixels[row_start + x as usize] = value;
+    }
+}
+
+#[inline(always)]
+fn get_pixel(pixels: &[u8], row_start: usize, x: u32, depth: u8) -> u8 {
+    if depth == 4 {
+        nibble_or_byte(pixels[row_start + x as usize / 2], 4, x as usize)
+    } else {
+        pixels[row_start + x as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::encode;
+    use super::set_pixel;
+
+    #[test]
+    fn decode_rle8() {
+        // example from BMP documentation
+        let mut data = [
+            0x03, 0x04, 0x05, 0x06, 0x00, 0x03, 0x45, 0x56, 0x67, 0x00, 0x02, 0x78,
+            0x00, 0x02, 0x05, 0x01, 0x02, 0x78, 0x00, 0x00, 0x09, 0x1E, 0x00, 0x01,
+        ];
+        let pixels = decode(&data, 32, 3, 8, 32).unwrap();
+        assert_eq!(&pixels[..14], &[4, 4, 4, 6, 6, 6, 6, 6, 0x45, 0x56, 0x67, 0x78, 0x78, 0]);
+        assert_eq!(&pixels[32 + 17..32 + 21], &[0, 0x78, 0x78, 0]);
+        assert_eq!(&pixels[64..74], &[0x1e, 0x1e, 0x1e, 0x1e, 0x1e, 0x1e, 0x1e, 0x1e, 0x1e, 0]);
+
+        // too many pixels for the third row
+        data[20] = 33;
+        assert!(decode(&data, 32, 3, 8, 32).is_err());
+
+        // nothing is allocated for huge dimensions
+        assert!(decode(&data, u32::MAX, 1 << 31, 8, u32::MAX as usize).is_err());
+        assert!(decode(&data, u32::MAX, u32::MAX, 8, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn decode_rle4() {
+        // example from BMP documentation
+        let data = [
+            0x03, 0x04, 0x05, 0x06, 0x00, 0x06, 0x45, 0x56, 0x67, 0x00, 0x04, 0x78,
+            0x00, 0x02, 0x05, 0x01, 0x04, 0x78, 0x00, 0x00, 0x09, 0x1E, 0x00, 0x01,
+        ];
+        let pixels = decode(&data, 32, 3, 4, 16).unwrap();
+        assert_eq!(&pixels[..10], &[0x04, 0x00, 0x60, 0x60, 0x45, 0x56, 0x67, 0x78, 0x78, 0]);
+        assert_eq!(&pixels[16 + 10..16 + 15], &[0, 0x07, 0x87, 0x80, 0]);
+        assert_eq!(&pixels[32..38], &[0x1e, 0x1e, 0x1e, 0x1e, 0x10, 0]);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for &(depth, row_length) in &[(8u8, 20usize), (4u8, 12usize)] {
+            let mut pixels = vec![0u8; row_length * 4];
+            for y in 0..4 {
+                for x in 0..19u32 {
+                    // runs and literals of several lengths
+                    let value = (x / (y as u32 + 1) % 3 * 5 + x % 2 * (y as u32 % 2)) as u8;
+                    set_pixel(&mut pixels, row_length * y, x, depth, value & 0xf);
+                }
+            }
+            let data = encode(&pixels, 19, 4, depth, row_length);
+            assert_eq!(&data[data.len() - 2..], &[0, 1]);
+            assert_eq!(decode(&data, 19, 4, depth, row_length).unwrap(), pixels);
+        }
+    }
+}
@@ -0,0 +1,500 @@
+use std::vec::Vec;
+
+use argon2::Algorithm;
+use argon2::Argon2;
+use argon2::Params;
+use argon2::Version;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::Key;
+use chacha20poly1305::Nonce;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::KeyInit;
+use chacha20poly1305::aead::Payload as AeadPayload;
+use ed25519_dalek::Signature;
+use ed25519_dalek::Signer;
+use ed25519_dalek::SigningKey;
+use ed25519_dalek::VerifyingKey;
+use getrandom::getrandom;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::PublicKey;
+use x25519_dalek::StaticSecret;
+
+use super::error::Error;
+use super::payload::FLAG_ENCRYPTED;
+use super::payload::FLAG_SIGNED;
+use super::payload::Kind;
+use super::payload::Payload;
+use super::util::read_le_u32;
+use super::util::write_le_u32;
+
+/// Key derived from a password with Argon2id, body sealed with ChaCha20-Poly1305
+///
+/// Encrypted bodies start with the scheme, then for this one:
+/// m_cost (4), t_cost (4), p_cost (4), salt (16), nonce (12),
+/// and the ciphertext followed by its tag (16)
+pub const SCHEME_PASSWORD: u8 = 1;
+
+/// Per message key wrapped for each X25519 recipient, body sealed with
+/// ChaCha20-Poly1305 under that key
+///
+/// After the scheme: an ephemeral public key (32), the number of
+/// recipients (2), a wrapped key (48) per recipient, nonce (12) and the
+/// ciphertext with its tag (16). A recipient's wrapping key is HKDF-SHA256
+/// of the shared secret, salted with both public keys.
+pub const SCHEME_RECIPIENTS: u8 = 2;
+
+const WRAP_INFO: &'static [u8] = b"entrepixels x25519 v1";
+
+/// Salt of the seed of pixel order, followed by the width and height of
+/// the image so that a key gives another order in images of another
+/// size. Images of the same size share it, nothing else in them is sure
+/// to be left alone by hiding
+const ORDER_SALT: &'static [u8] = b"entrepixels order v1";
+const WRAPPED_KEY_SIZE: usize = KEY_SIZE + 16;
+
+/// Signed bodies start with the signer's public key (32) and the
+/// signature (64) of the context, kind, flags and the rest of the body
+const SIGNATURE_CONTEXT: &'static [u8] = b"entrepixels ed25519 v1";
+const SIGNATURE_SIZE: usize = 64;
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const KEY_SIZE: usize = 32;
+const PASSWORD_HEADER_SIZE: usize = 1 + 12 + SALT_SIZE + NONCE_SIZE;
+
+/// Argon2id costs, memory in KiB
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+/// 64 MiB and 3 passes
+pub const DEFAULT_KDF_PARAMS: KdfParams = KdfParams {
+    m_cost: 64 * 1024,
+    t_cost: 3,
+    p_cost: 1,
+};
+
+/// Highest costs accepted when showing, 256 MiB and 16 passes. Kept
+/// apart from the defaults so images hidden with other defaults can
+/// still be shown, but low enough that a crafted image can't make us
+/// allocate or spin for long before failing to decrypt
+const MAX_KDF_PARAMS: KdfParams = KdfParams {
+    m_cost: 256 * 1024,
+    t_cost: 16,
+    p_cost: 4,
+};
+
+/// What was found checking the signature of a payload
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verification {
+    /// Signed by this key
+    Valid(VerifyingKey),
+    /// Forged, altered or signed by another key than the expected one
+    Invalid,
+    /// Not signed
+    Missing,
+}
+
+/// Encrypt the body of `payload` with a key derived from `password`
+pub fn seal_with_password(payload: &mut Payload, password: &[u8]) -> Result<(), Error> {
+    seal_with_params(payload, password, &DEFAULT_KDF_PARAMS)
+}
+
+fn seal_with_params(payload: &mut Payload, password: &[u8], params: &KdfParams) -> Result<(), Error> {
+    let mut header = vec![0u8; PASSWORD_HEADER_SIZE];
+    header[0] = SCHEME_PASSWORD;
+    write_le_u32(&mut header, 1, params.m_cost);
+    write_le_u32(&mut header, 5, params.t_cost);
+    write_le_u32(&mut header, 9, params.p_cost);
+    try!(random_bytes(&mut header[13..]));
+
+    let key = try!(derive_key(password, &header[13..13 + SALT_SIZE], params));
+    payload.flags |= FLAG_ENCRYPTED;
+    let body = try!(encrypt(&key, &header[13 + SALT_SIZE..], &payload.body, &aad(payload.kind, payload.flags)));
+
+    header.extend(body);
+    payload.body = header;
+    Ok(())
+}
+
+/// Encrypt the body of `payload` so that any of `recipients` can decrypt it
+pub fn seal_for_recipients(payload: &mut Payload, recipients: &[PublicKey]) -> Result<(), Error> {
+    if recipients.is_empty() || recipients.len() > 0xffff {
+        return Err(Error::new("Invalid number of recipients"))
+    }
+
+    let mut bytes = [0u8; KEY_SIZE];
+    try!(random_bytes(&mut bytes));
+    let ephemeral = StaticSecret::from(bytes);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let mut message_key = [0u8; KEY_SIZE];
+    try!(random_bytes(&mut message_key));
+
+    let mut body = vec![SCHEME_RECIPIENTS];
+    body.extend_from_slice(ephemeral_public.as_bytes());
+    body.push(recipients.len() as u8);
+    body.push((recipients.len() >> 8) as u8);
+    for recipient in recipients {
+        let shared = ephemeral.diffie_hellman(recipient);
+        if !shared.was_contributory() {
+            return Err(Error::new("Invalid recipient key"))
+        }
+        let key = try!(wrapping_key(shared.as_bytes(), &ephemeral_public, recipient));
+        body.extend(try!(encrypt(&key, &[0u8; NONCE_SIZE], &message_key, &[])));
+    }
+
+    let mut nonce = [0u8; NONCE_SIZE];
+    try!(random_bytes(&mut nonce));
+    payload.flags |= FLAG_ENCRYPTED;
+    let ciphertext = try!(encrypt(&message_key, &nonce, &payload.body, &aad(payload.kind, payload.flags)));
+    body.extend_from_slice(&nonce);
+    body.extend(ciphertext);
+
+    payload.body = body;
+    Ok(())
+}
+
+/// Decrypt the body of an encrypted `payload`, others are left as they are
+pub fn open(payload: &mut Payload, password: Option<&[u8]>, identity: Option<&StaticSecret>) -> Result<(), Error> {
+    if payload.flags & FLAG_ENCRYPTED == 0 {
+        return Ok(())
+    }
+    if payload.body.is_empty() {
+        return Err(Error::new("Invalid encrypted message"))
+    }
+
+    let body = match payload.body[0] {
+        SCHEME_PASSWORD => {
+            let password = match password {
+                Some(password) => password,
+                None => return Err(Error::new("Message is encrypted, a password is needed")),
+            };
+            if payload.body.len() < PASSWORD_HEADER_SIZE {
+                return Err(Error::new("Invalid encrypted message"))
+            }
+            let params = KdfParams {
+                m_cost: read_le_u32(&payload.body, 1),
+                t_cost: read_le_u32(&payload.body, 5),
+                p_cost: read_le_u32(&payload.body, 9),
+            };
+            if params.m_cost > MAX_KDF_PARAMS.m_cost || params.t_cost > MAX_KDF_PARAMS.t_cost ||
+                params.p_cost > MAX_KDF_PARAMS.p_cost {
+                return Err(Error::new("Unsupported key derivation parameters"))
+            }
+
+            let salt = &payload.body[13..13 + SALT_SIZE];
+            let nonce = &payload.body[13 + SALT_SIZE..PASSWORD_HEADER_SIZE];
+            let key = try!(derive_key(password, salt, &params));
+            try!(decrypt(&key, nonce, &payload.body[PASSWORD_HEADER_SIZE..], &aad(payload.kind, payload.flags)))
+        },
+        SCHEME_RECIPIENTS => {
+            let identity = match identity {
+                Some(identity) => identity,
+                None => return Err(Error::new("Message is encrypted to recipients, an identity is needed")),
+            };
+            try!(open_for_identity(payload, identity))
+        },
+        _ => return Err(Error::new("Unsupported encryption scheme")),
+    };
+
+    payload.body = body;
+    payload.flags &= !FLAG_ENCRYPTED;
+    Ok(())
+}
+
+/// Sign the body of `payload`, before it gets encrypted
+pub fn sign(payload: &mut Payload, key: &SigningKey) {
+    payload.flags |= FLAG_SIGNED;
+    let signature = key.sign(&signed_data(payload.kind, payload.flags, &payload.body));
+
+    let mut body = Vec::with_capacity(KEY_SIZE + SIGNATURE_SIZE + payload.body.len());
+    body.extend_from_slice(key.verifying_key().as_bytes());
+    body.extend_from_slice(&signature.to_bytes());
+    body.extend_from_slice(&payload.body);
+    payload.body = body;
+}
+
+/// Check and strip the signature of a decrypted `payload`, signed ones
+/// are only valid if signed by `signer` when it is given
+pub fn verify(payload: &mut Payload, signer: Option<&VerifyingKey>) -> Result<Verification, Error> {
+    if payload.flags & FLAG_SIGNED == 0 {
+        return Ok(Verification::Missing)
+    }
+    if payload.body.len() < KEY_SIZE + SIGNATURE_SIZE {
+        return Err(Error::new("Invalid signed message"))
+    }
+
+    let body = payload.body.split_off(KEY_SIZE + SIGNATURE_SIZE);
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&payload.body[..KEY_SIZE]);
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    signature.copy_from_slice(&payload.body[KEY_SIZE..]);
+    payload.body = body;
+
+    let key = match VerifyingKey::from_bytes(&key) {
+        Ok(key) => key,
+        Err(_) => return Ok(Verification::Invalid),
+    };
+    if signer.map_or(false, |signer| *signer != key) {
+        return Ok(Verification::Invalid)
+    }
+    let data = signed_data(payload.kind, payload.flags, &payload.body);
+    match key.verify_strict(&data, &Signature::from_bytes(&signature)) {
+        Ok(_) => Ok(Verification::Valid(key)),
+        Err(_) => Ok(Verification::Invalid),
+    }
+}
+
+fn signed_data(kind: Kind, flags: u16, body: &[u8]) -> Vec<u8> {
+    let mut data = SIGNATURE_CONTEXT.to_vec();
+    data.extend_from_slice(&aad(kind, flags & !FLAG_ENCRYPTED));
+    data.extend_from_slice(body);
+    data
+}
+
+/// Seed of the pixel order of a `width` x `height` image from the key
+/// given to hide and show
+pub fn order_seed(key: &[u8], width: u32, height: u32) -> Result<[u8; KEY_SIZE], Error> {
+    let mut salt = ORDER_SALT.to_vec();
+    salt.extend_from_slice(&[0u8; 8]);
+    write_le_u32(&mut salt, ORDER_SALT.len(), width);
+    write_le_u32(&mut salt, ORDER_SALT.len() + 4, height);
+    derive_key(key, &salt, &DEFAULT_KDF_PARAMS)
+}
+
+fn open_for_identity(payload: &Payload, identity: &StaticSecret) -> Result<Vec<u8>, Error> {
+    let body = &payload.body;
+    if body.len() < 1 + KEY_SIZE + 2 {
+        return Err(Error::new("Invalid encrypted message"))
+    }
+    let mut ephemeral_public = [0u8; KEY_SIZE];
+    ephemeral_public.copy_from_slice(&body[1..1 + KEY_SIZE]);
+    let ephemeral_public = PublicKey::from(ephemeral_public);
+    let count = body[1 + KEY_SIZE] as usize | (body[2 + KEY_SIZE] as usize) << 8;
+    let keys_start = 3 + KEY_SIZE;
+    let keys_end = keys_start + count * WRAPPED_KEY_SIZE;
+    if body.len() < keys_end + NONCE_SIZE {
+        return Err(Error::new("Invalid encrypted message"))
+    }
+
+    let shared = identity.diffie_hellman(&ephemeral_public);
+    if !shared.was_contributory() {
+        return Err(Error::new("Invalid encrypted message"))
+    }
+    let key = try!(wrapping_key(shared.as_bytes(), &ephemeral_public, &PublicKey::from(identity)));
+    // recipients are anonymous, try every wrapped key
+    let message_key = body[keys_start..keys_end].chunks(WRAPPED_KEY_SIZE)
+        .filter_map(|wrapped| decrypt(&key, &[0u8; NONCE_SIZE], wrapped, &[]).ok())
+        .next();
+    let message_key = match message_key {
+        Some(message_key) => message_key,
+        None => return Err(Error::new("Message is not encrypted to this identity")),
+    };
+
+    let nonce = &body[keys_end..keys_end + NONCE_SIZE];
+    match decrypt(&message_key, nonce, &body[keys_end + NONCE_SIZE..], &aad(payload.kind, payload.flags)) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(_) => Err(Error::new("Corrupted message")),
+    }
+}
+
+fn wrapping_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> Result<[u8; KEY_SIZE], Error> {
+    let mut salt = [0u8; 2 * KEY_SIZE];
+    salt[..KEY_SIZE].copy_from_slice(ephemeral.as_bytes());
+    salt[KEY_SIZE..].copy_from_slice(recipient.as_bytes());
+    let mut key = [0u8; KEY_SIZE];
+    match Hkdf::<Sha256>::new(Some(&salt), shared).expand(WRAP_INFO, &mut key) {
+        Ok(_) => Ok(key),
+        Err(_) => Err(Error::new("Key derivation failed")),
+    }
+}
+
+/// The payload header fields the ciphertext and signature are bound to
+fn aad(kind: Kind, flags: u16) -> [u8; 3] {
+    [kind.to_u8(), flags as u8, (flags >> 8) as u8]
+}
+
+fn derive_key(password: &[u8], salt: &[u8], params: &KdfParams) -> Result<[u8; KEY_SIZE], Error> {
+    let params = match Params::new(params.m_cost, params.t_cost, params.p_cost, Some(KEY_SIZE)) {
+        Ok(params) => params,
+        Err(_) => return Err(Error::new("Invalid key derivation parameters")),
+    };
+    let mut key = [0u8; KEY_SIZE];
+    match Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(password, salt, &mut key) {
+        Ok(_) => Ok(key),
+        Err(_) => Err(Error::new("Key derivation failed")),
+    }
+}
+
+fn encrypt(key: &[u8], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    match cipher.encrypt(Nonce::from_slice(nonce), AeadPayload { msg: plaintext, aad: aad }) {
+        Ok(ciphertext) => Ok(ciphertext),
+        Err(_) => Err(Error::new("Encryption failed")),
+    }
+}
+
+fn decrypt(key: &[u8], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    match cipher.decrypt(Nonce::from_slice(nonce), AeadPayload { msg: ciphertext, aad: aad }) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(_) => Err(Error::new("Wrong password or corrupted message")),
+    }
+}
+
+fn random_bytes(buf: &mut [u8]) -> Result<(), Error> {
+    match getrandom(buf) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::new("Can't get random bytes")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x25519_dalek::PublicKey;
+
+    use keys::generate_ed25519;
+    use keys::generate_x25519;
+    use payload::FLAG_ENCRYPTED;
+    use payload::FLAG_SIGNED;
+    use payload::Kind;
+    use payload::Payload;
+    use super::KdfParams;
+    use super::MAX_KDF_PARAMS;
+    use super::open;
+    use super::seal_for_recipients;
+    use super::seal_with_params;
+    use super::sign;
+    use super::verify;
+    use super::Verification;
+
+    // cheap costs, the defaults are far too slow for debug builds
+    const PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn sealed(password: &[u8]) -> Payload {
+        let mut payload = Payload::new(Kind::Message, b"attack at dawn".to_vec());
+        seal_with_params(&mut payload, password, &PARAMS).unwrap();
+        payload
+    }
+
+    #[test]
+    fn password_round_trip() {
+        let mut payload = sealed(b"secret");
+        assert_eq!(payload.flags, FLAG_ENCRYPTED);
+        assert_eq!(payload.body.len(), 1 + 12 + 16 + 12 + 14 + 16);
+        assert!(sealed(b"secret").body != payload.body);
+
+        open(&mut payload, Some(b"secret"), None).unwrap();
+        assert_eq!(payload, Payload::new(Kind::Message, b"attack at dawn".to_vec()));
+
+        // plain payloads need no password
+        open(&mut payload, None, None).unwrap();
+        assert_eq!(payload.body, b"attack at dawn".to_vec());
+    }
+
+    #[test]
+    fn authentication_failures() {
+        assert!(open(&mut sealed(b"secret"), Some(b"Secret"), None).is_err());
+        assert!(open(&mut sealed(b"secret"), None, None).is_err());
+
+        let mut tampered = sealed(b"secret");
+        let last = tampered.body.len() - 1;
+        tampered.body[last] ^= 1;
+        assert!(open(&mut tampered, Some(b"secret"), None).is_err());
+
+        // header fields are authenticated too
+        let mut tampered = sealed(b"secret");
+        tampered.kind = Kind::File;
+        assert!(open(&mut tampered, Some(b"secret"), None).is_err());
+
+        let mut expensive = sealed(b"secret");
+        expensive.body[4] = 0xff;
+        assert!(open(&mut expensive, Some(b"secret"), None).is_err());
+        let mut expensive = sealed(b"secret");
+        expensive.body[5] = MAX_KDF_PARAMS.t_cost as u8 + 1;
+        let err = open(&mut expensive, Some(b"secret"), None).err().unwrap();
+        assert_eq!(format!("{}", err), "Err: Unsupported key derivation parameters");
+
+        let mut truncated = sealed(b"secret");
+        truncated.body.truncate(20);
+        assert!(open(&mut truncated, Some(b"secret"), None).is_err());
+    }
+
+    #[test]
+    fn recipients_round_trip() {
+        let alice = generate_x25519().unwrap();
+        let bob = generate_x25519().unwrap();
+        let eve = generate_x25519().unwrap();
+        let recipients = [PublicKey::from(&alice), PublicKey::from(&bob)];
+
+        let mut payload = Payload::new(Kind::File, b"keys".to_vec());
+        seal_for_recipients(&mut payload, &recipients).unwrap();
+        assert_eq!(payload.body.len(), 1 + 32 + 2 + 2 * 48 + 12 + 4 + 16);
+
+        for identity in &[&alice, &bob] {
+            let mut opened = payload.clone();
+            open(&mut opened, None, Some(identity)).unwrap();
+            assert_eq!(opened, Payload::new(Kind::File, b"keys".to_vec()));
+        }
+
+        assert!(open(&mut payload.clone(), None, Some(&eve)).is_err());
+        assert!(open(&mut payload.clone(), Some(b"secret"), None).is_err());
+
+        let mut tampered = payload.clone();
+        let last = tampered.body.len() - 1;
+        tampered.body[last] ^= 1;
+        assert!(open(&mut tampered, None, Some(&alice)).is_err());
+
+        assert!(seal_for_recipients(&mut Payload::new(Kind::File, vec![]), &[]).is_err());
+    }
+
+    #[test]
+    fn signatures() {
+        let alice = generate_ed25519().unwrap();
+        let mallory = generate_ed25519().unwrap();
+        let plain = Payload::new(Kind::Message, b"attack at dawn".to_vec());
+
+        let mut signed = plain.clone();
+        sign(&mut signed, &alice);
+        assert_eq!(signed.flags, FLAG_SIGNED);
+        assert_eq!(signed.body.len(), 32 + 64 + 14);
+
+        // signed then encrypted
+        let mut sealed = signed.clone();
+        seal_with_params(&mut sealed, b"secret", &PARAMS).unwrap();
+        open(&mut sealed, Some(b"secret"), None).unwrap();
+        assert_eq!(verify(&mut sealed, Some(&alice.verifying_key())).unwrap(),
+                   Verification::Valid(alice.verifying_key()));
+        assert_eq!(sealed.body, plain.body);
+
+        assert_eq!(verify(&mut signed.clone(), None).unwrap(), Verification::Valid(alice.verifying_key()));
+        assert_eq!(verify(&mut signed.clone(), Some(&mallory.verifying_key())).unwrap(), Verification::Invalid);
+        assert_eq!(verify(&mut plain.clone(), Some(&alice.verifying_key())).unwrap(), Verification::Missing);
+
+        // message and metadata are covered
+        let mut tampered = signed.clone();
+        let last = tampered.body.len() - 1;
+        tampered.body[last] ^= 1;
+        assert_eq!(verify(&mut tampered, None).unwrap(), Verification::Invalid);
+        let mut tampered = signed.clone();
+        tampered.kind = Kind::File;
+        assert_eq!(verify(&mut tampered, None).unwrap(), Verification::Invalid);
+
+        // resigned by someone else, with their own key in the body
+        let mut forged = plain.clone();
+        sign(&mut forged, &mallory);
+        assert_eq!(verify(&mut forged, Some(&alice.verifying_key())).unwrap(), Verification::Invalid);
+
+        let mut truncated = signed.clone();
+        truncated.body.truncate(90);
+        assert!(verify(&mut truncated, None).is_err());
+    }
+}
@@ -0,0 +1,513 @@
+use std::io::Read;
+use std::vec::Vec;
+
+use super::error::Error;
+use super::util::read_le_u16;
+use super::util::read_le_u32;
+use super::util::write_le_u16;
+use super::util::write_le_u32;
+
+/// First bytes of a frame, after decoding its header
+pub const MAGIC: [u8; 4] = [b'E', b'P', b'X', b'R'];
+
+/// magic (4), parity (1), depth (2), length (4)
+const HEADER_DATA_SIZE: usize = 11;
+
+/// The frame header has its own code, it can't depend on what it describes
+const HEADER_PARITY: usize = 8;
+
+/// Bytes taken by the encoded frame header
+pub const HEADER_SIZE: usize = HEADER_DATA_SIZE + HEADER_PARITY;
+
+/// Symbols in a Reed-Solomon code word over GF(2^8)
+const BLOCK_SIZE: usize = 255;
+
+pub const MIN_PARITY: u8 = 2;
+pub const MAX_PARITY: u8 = 128;
+
+/// How data is protected: `parity` symbols per block of 255, which
+/// correct up to `parity / 2` wrong bytes in each block, and the
+/// `depth` of interleaving, how many blocks have their bytes spread
+/// among each other so a damaged area hits many blocks a little
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EccParams {
+    pub parity: u8,
+    /// 0 interleaves all blocks together
+    pub depth: u16,
+}
+
+/// Frame header: the parameters data was encoded with and its length
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub parity: u8,
+    pub depth: u16,
+    pub length: u32,
+    /// symbols corrected in the header itself
+    pub corrected: usize,
+}
+
+impl Frame {
+
+    /// Decode a frame header, `None` means there is no frame
+    pub fn parse(header: &[u8]) -> Option<Frame> {
+        if header.len() != HEADER_SIZE {
+            return None
+        }
+        let code = ReedSolomon::new(HEADER_PARITY);
+        let mut header = header.to_vec();
+        let corrected = match code.correct(&mut header) {
+            Some(corrected) => corrected,
+            None => return None,
+        };
+        if header[..4] != MAGIC {
+            return None
+        }
+        let frame = Frame {
+            parity: header[4],
+            depth: read_le_u16(&header, 5),
+            length: read_le_u32(&header, 7),
+            corrected: corrected,
+        };
+        if frame.parity < MIN_PARITY || frame.parity > MAX_PARITY || frame.depth == 0 {
+            return None
+        }
+        Some(frame)
+    }
+
+    /// Bytes taken by the encoded data, after the header
+    pub fn encoded_len(&self) -> u64 {
+        blocks_len(self.length as u64, self.parity)
+    }
+
+    /// Read and correct the data following the header, returns it
+    /// with the number of symbols corrected, header included
+    pub fn read_data<R: Read>(&self, input: &mut R) -> Result<(Vec<u8>, usize), Error> {
+        let lengths = self.block_lengths();
+        let mut encoded = vec![0u8; self.encoded_len() as usize];
+        match input.read_exact(&mut encoded) {
+            Ok(_) => {},
+            Err(_) => return Err(Error::new("No message found or message truncated")),
+        }
+
+        let mut blocks = deinterleave(&encoded, &lengths, self.depth as usize);
+        let code = ReedSolomon::new(self.parity as usize);
+        let mut corrected = self.corrected;
+        let mut data = Vec::with_capacity(self.length as usize);
+        for block in blocks.iter_mut() {
+            match code.correct(block) {
+                Some(count) => corrected += count,
+                None => return Err(Error::new("Corrupted message: too many errors to correct")),
+            }
+            let data_size = block.len() - self.parity as usize;
+            data.extend_from_slice(&block[..data_size]);
+        }
+        Ok((data, corrected))
+    }
+
+    fn block_lengths(&self) -> Vec<usize> {
+        let data_size = BLOCK_SIZE - self.parity as usize;
+        let mut lengths = Vec::new();
+        let mut left = self.length as usize;
+        while left > 0 {
+            let size = if left < data_size { left } else { data_size };
+            lengths.push(size + self.parity as usize);
+            left -= size;
+        }
+        lengths
+    }
+}
+
+/// Encode `data` with a frame header, ready to be hidden
+pub fn encode(data: &[u8], params: &EccParams) -> Result<Vec<u8>, Error> {
+    if params.parity < MIN_PARITY || params.parity > MAX_PARITY {
+        return Err(Error::new(&format!("Error correction parity must be between {} and {}",
+                                       MIN_PARITY, MAX_PARITY)))
+    }
+    let mut frame = Frame {
+        parity: params.parity,
+        depth: params.depth,
+        length: data.len() as u32,
+        corrected: 0,
+    };
+    if frame.depth == 0 {
+        let blocks = frame.block_lengths().len();
+        frame.depth = if blocks > 0xffff { 0xffff } else if blocks == 0 { 1 } else { blocks as u16 };
+    }
+
+    let mut header = vec![0u8; HEADER_DATA_SIZE];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4] = frame.parity;
+    write_le_u16(&mut header, 5, frame.depth);
+    write_le_u32(&mut header, 7, frame.length);
+    let mut encoded = ReedSolomon::new(HEADER_PARITY).encode(&header);
+
+    let code = ReedSolomon::new(frame.parity as usize);
+    let data_size = BLOCK_SIZE - frame.parity as usize;
+    let blocks: Vec<Vec<u8>> = data.chunks(data_size).map(|chunk| code.encode(chunk)).collect();
+    encoded.extend(interleave(&blocks, frame.depth as usize));
+    Ok(encoded)
+}
+
+/// Bytes taken by `length` bytes of data once encoded, header included
+pub fn encoded_len(length: u64, params: &EccParams) -> u64 {
+    HEADER_SIZE as u64 + blocks_len(length, params.parity)
+}
+
+fn blocks_len(length: u64, parity: u8) -> u64 {
+    let data_size = (BLOCK_SIZE - parity as usize) as u64;
+    let blocks = length.div_ceil(data_size);
+    length + blocks * parity as u64
+}
+
+/// Bytes of groups of `depth` blocks are written in turns,
+/// the first byte of each block, then the second...
+fn interleave(blocks: &[Vec<u8>], depth: usize) -> Vec<u8> {
+    let mut output = Vec::new();
+    for group in blocks.chunks(depth) {
+        let longest = group.iter().map(|block| block.len()).max().unwrap_or(0);
+        for position in 0..longest {
+            for block in group {
+                if position < block.len() {
+                    output.push(block[position]);
+                }
+            }
+        }
+    }
+    output
+}
+
+fn deinterleave(data: &[u8], lengths: &[usize], depth: usize) -> Vec<Vec<u8>> {
+    let mut blocks = Vec::with_capacity(lengths.len());
+    let mut offset = 0;
+    for group in lengths.chunks(depth) {
+        let mut group_blocks: Vec<Vec<u8>> = group.iter().map(|length| Vec::with_capacity(*length)).collect();
+        let longest = group.iter().cloned().max().unwrap_or(0);
+        for position in 0..longest {
+            for (block, length) in group_blocks.iter_mut().zip(group) {
+                if position < *length {
+                    block.push(data[offset]);
+                    offset += 1;
+                }
+            }
+        }
+        blocks.extend(group_blocks);
+    }
+    blocks
+}
+
+/// Systematic Reed-Solomon code over GF(2^8) (polynomial 0x11d) with
+/// `parity` check symbols, code words shorter than 255 are shortened codes
+///
+/// Polynomials are vectors of coefficients, highest degree first
+struct ReedSolomon {
+    exp: [u8; 512],
+    log: [u8; 256],
+    generator: Vec<u8>,
+    parity: usize,
+}
+
+impl ReedSolomon {
+
+    fn new(parity: usize) -> ReedSolomon {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        let mut x = 1u32;
+        for i in 0..255 {
+            exp[i] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        // products index up to 254 + 254, without reducing modulo 255
+        let (low, high) = exp.split_at_mut(255);
+        high[..255].copy_from_slice(low);
+
+        let mut code = ReedSolomon {
+            exp: exp,
+            log: log,
+            generator: vec![1],
+            parity: parity,
+        };
+        for i in 0..parity {
+            let root = code.exp[i];
+            code.generator = code.poly_mul(&code.generator, &[1, root]);
+        }
+        code
+    }
+
+    /// `data` followed by its check symbols
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut dividend = data.to_vec();
+        dividend.extend(vec![0u8; self.parity]);
+        let remainder = self.poly_div(&dividend, &self.generator);
+        let mut block = data.to_vec();
+        block.extend(remainder);
+        block
+    }
+
+    /// Fix `block` in place, returns how many symbols were wrong
+    /// or `None` when there are too many errors
+    fn correct(&self, block: &mut [u8]) -> Option<usize> {
+        // with a leading 0, as the locator and evaluator expect it
+        let mut syndromes = vec![0u8; self.parity + 1];
+        for i in 0..self.parity {
+            syndromes[i + 1] = self.poly_eval(block, self.exp[i]);
+        }
+        if syndromes.iter().all(|s| *s == 0) {
+            return Some(0)
+        }
+
+        let locator = match self.error_locator(&syndromes) {
+            Some(locator) => locator,
+            None => return None,
+        };
+        let mut reversed = locator.clone();
+        reversed.reverse();
+        let positions = match self.error_positions(&reversed, block.len()) {
+            Some(positions) => positions,
+            None => return None,
+        };
+
+        self.correct_errors(block, &syndromes, &positions);
+
+        for i in 0..self.parity {
+            if self.poly_eval(block, self.exp[i]) != 0 {
+                return None
+            }
+        }
+        Some(positions.len())
+    }
+
+    /// Berlekamp-Massey
+    fn error_locator(&self, syndromes: &[u8]) -> Option<Vec<u8>> {
+        let mut locator = vec![1u8];
+        let mut old_locator = vec![1u8];
+        let shift = syndromes.len() - self.parity;
+
+        for i in 0..self.parity {
+            let k = i + shift;
+            let mut delta = syndromes[k];
+            for j in 1..locator.len() {
+                delta ^= self.mul(locator[locator.len() - 1 - j], syndromes[k - j]);
+            }
+            old_locator.push(0);
+            if delta != 0 {
+                if old_locator.len() > locator.len() {
+                    let new_locator = self.poly_scale(&old_locator, delta);
+                    old_locator = self.poly_scale(&locator, self.inverse(delta));
+                    locator = new_locator;
+                }
+                locator = poly_add(&locator, &self.poly_scale(&old_locator, delta));
+            }
+        }
+
+        while locator.len() > 1 && locator[0] == 0 {
+            locator.remove(0);
+        }
+        if (locator.len() - 1) * 2 > self.parity {
+            return None
+        }
+        Some(locator)
+    }
+
+    /// Chien search, the roots of the locator are the error positions
+    fn error_positions(&self, locator: &[u8], length: usize) -> Option<Vec<usize>> {
+        let errors = locator.len() - 1;
+        let mut positions = Vec::new();
+        for i in 0..length {
+            if self.poly_eval(locator, self.exp[i]) == 0 {
+                positions.push(length - 1 - i);
+            }
+        }
+        if positions.len() != errors {
+            return None
+        }
+        Some(positions)
+    }
+
+    /// Forney algorithm, finds the error values at known positions
+    fn correct_errors(&self, block: &mut [u8], syndromes: &[u8], positions: &[usize]) {
+        let coefficients: Vec<usize> = positions.iter().map(|position| block.len() - 1 - position).collect();
+
+        let mut locator = vec![1u8];
+        for coefficient in &coefficients {
+            locator = self.poly_mul(&locator, &[self.exp[*coefficient], 1]);
+        }
+
+        let mut reversed_syndromes = syndromes.to_vec();
+        reversed_syndromes.reverse();
+        let product = self.poly_mul(&reversed_syndromes, &locator);
+        let mut divisor = vec![0u8; locator.len() + 1];
+        divisor[0] = 1;
+        let evaluator = self.poly_div(&product, &divisor);
+
+        let roots: Vec<u8> = coefficients.iter().map(|coefficient| self.exp[*coefficient]).collect();
+        for (i, root) in roots.iter().enumerate() {
+            let root_inverse = self.inverse(*root);
+            let mut locator_prime = 1u8;
+            for (j, other) in roots.iter().enumerate() {
+                if j != i {
+                    locator_prime = self.mul(locator_prime, 1 ^ self.mul(root_inverse, *other));
+                }
+            }
+            let y = self.mul(*root, self.poly_eval(&evaluator, root_inverse));
+            block[positions[i]] ^= self.div(y, locator_prime);
+        }
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    fn div(&self, a: u8, b: u8) -> u8 {
+        if a == 0 {
+            return 0
+        }
+        self.exp[(self.log[a as usize] as usize + 255 - self.log[b as usize] as usize) % 255]
+    }
+
+    fn inverse(&self, a: u8) -> u8 {
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    fn poly_scale(&self, p: &[u8], x: u8) -> Vec<u8> {
+        p.iter().map(|coefficient| self.mul(*coefficient, x)).collect()
+    }
+
+    fn poly_mul(&self, p: &[u8], q: &[u8]) -> Vec<u8> {
+        let mut r = vec![0u8; p.len() + q.len() - 1];
+        for (j, b) in q.iter().enumerate() {
+            for (i, a) in p.iter().enumerate() {
+                r[i + j] ^= self.mul(*a, *b);
+            }
+        }
+        r
+    }
+
+    /// Horner's method
+    fn poly_eval(&self, p: &[u8], x: u8) -> u8 {
+        p.iter().fold(0, |y, coefficient| self.mul(y, x) ^ coefficient)
+    }
+
+    /// Remainder of the division by a monic polynomial
+    fn poly_div(&self, dividend: &[u8], divisor: &[u8]) -> Vec<u8> {
+        let mut output = dividend.to_vec();
+        for i in 0..dividend.len() - (divisor.len() - 1) {
+            let coefficient = output[i];
+            if coefficient != 0 {
+                for j in 1..divisor.len() {
+                    output[i + j] ^= self.mul(divisor[j], coefficient);
+                }
+            }
+        }
+        output.split_off(dividend.len() - (divisor.len() - 1))
+    }
+}
+
+fn poly_add(p: &[u8], q: &[u8]) -> Vec<u8> {
+    let length = if p.len() > q.len() { p.len() } else { q.len() };
+    let mut r = vec![0u8; length];
+    for (i, coefficient) in p.iter().enumerate() {
+        r[i + length - p.len()] = *coefficient;
+    }
+    for (i, coefficient) in q.iter().enumerate() {
+        r[i + length - q.len()] ^= *coefficient;
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::encode;
+    use super::encoded_len;
+    use super::EccParams;
+    use super::Frame;
+    use super::ReedSolomon;
+    use super::HEADER_SIZE;
+
+    fn decode(encoded: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let frame = match Frame::parse(&encoded[..HEADER_SIZE]) {
+            Some(frame) => frame,
+            None => return None,
+        };
+        frame.read_data(&mut Cursor::new(encoded[HEADER_SIZE..].to_vec())).ok()
+    }
+
+    /// Deterministic noise, for data and error positions
+    fn noise(seed: u32, length: usize) -> Vec<u8> {
+        let mut x = seed;
+        (0..length).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn reed_solomon_corrects_up_to_half_parity() {
+        let code = ReedSolomon::new(10);
+        let data = noise(1, 40);
+        let block = code.encode(&data);
+        assert_eq!(block.len(), 50);
+        assert_eq!(&block[..40], &data[..]);
+
+        for errors in 0..6 {
+            let mut damaged = block.clone();
+            for (i, position) in noise(errors as u32 + 7, errors).iter().enumerate() {
+                damaged[(*position as usize % 5) * 10 + i] ^= 0x5a;
+            }
+            assert_eq!(code.correct(&mut damaged), Some(errors));
+            assert_eq!(damaged, block);
+        }
+
+        let mut damaged = block.clone();
+        for position in 0..8 {
+            damaged[position * 6] ^= 0xff;
+        }
+        assert!(code.correct(&mut damaged) != Some(8));
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let data = noise(2, 1000);
+        let params = EccParams { parity: 32, depth: 0 };
+        let encoded = encode(&data, &params).unwrap();
+        assert_eq!(encoded.len() as u64, encoded_len(1000, &params));
+        assert_eq!(encoded.len(), 19 + 1000 + 5 * 32);
+        assert_eq!(decode(&encoded), Some((data.clone(), 0)));
+
+        // a burst of 60 bytes is spread over the 5 blocks
+        let mut damaged = encoded.clone();
+        for byte in &mut damaged[300..360] {
+            *byte ^= 0x01;
+        }
+        damaged[2] ^= 0x80;
+        assert_eq!(decode(&damaged), Some((data.clone(), 61)));
+
+        // without interleaving it all lands in one or two blocks
+        let params = EccParams { parity: 32, depth: 1 };
+        let mut damaged = encode(&data, &params).unwrap();
+        for byte in &mut damaged[300..360] {
+            *byte ^= 0x01;
+        }
+        assert_eq!(decode(&damaged), None);
+
+        assert!(encode(&data, &EccParams { parity: 1, depth: 0 }).is_err());
+        assert!(encode(&data, &EccParams { parity: 200, depth: 0 }).is_err());
+    }
+
+    #[test]
+    fn plain_data_is_not_a_frame() {
+        let mut header = vec![b'E', b'P', b'X', b'L', 1, 0, 0, 0, 5, 0, 0, 0];
+        header.extend(noise(3, HEADER_SIZE - header.len()));
+        assert_eq!(Frame::parse(&header), None);
+        assert_eq!(Frame::parse(&[0u8; HEADER_SIZE]), None);
+        assert_eq!(Frame::parse(&[0xffu8; HEADER_SIZE]), None);
+    }
+}
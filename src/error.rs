@@ -31,7 +31,7 @@ impl ErrorTrait for Error {
 
 impl From<IoError> for Error {
     fn from(err: IoError) -> Error {
-        Error::new(err.description())
+        Error::new(&err.to_string())
     }
 }
 
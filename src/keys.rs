@@ -0,0 +1,167 @@
+use std::string::String;
+use std::vec::Vec;
+
+use ed25519_dalek::SigningKey;
+use ed25519_dalek::VerifyingKey;
+use getrandom::getrandom;
+use x25519_dalek::PublicKey;
+use x25519_dalek::StaticSecret;
+
+use super::error::Error;
+
+/// Keys are written as a prefix telling their type and 32 bytes in hex
+pub const X25519_PUBLIC_PREFIX: &'static str = "x25519:";
+pub const X25519_SECRET_PREFIX: &'static str = "x25519-secret:";
+pub const ED25519_PUBLIC_PREFIX: &'static str = "ed25519:";
+pub const ED25519_SECRET_PREFIX: &'static str = "ed25519-secret:";
+
+const KEY_SIZE: usize = 32;
+
+pub fn generate_x25519() -> Result<StaticSecret, Error> {
+    Ok(StaticSecret::from(try!(random_key())))
+}
+
+pub fn generate_ed25519() -> Result<SigningKey, Error> {
+    Ok(SigningKey::from_bytes(&try!(random_key())))
+}
+
+pub fn format_x25519_public(key: &PublicKey) -> String {
+    format!("{}{}", X25519_PUBLIC_PREFIX, to_hex(key.as_bytes()))
+}
+
+pub fn parse_x25519_public(text: &str) -> Result<PublicKey, Error> {
+    Ok(PublicKey::from(try!(parse_key(text.trim(), X25519_PUBLIC_PREFIX))))
+}
+
+/// Contents of a secret key file, the public key goes in a comment
+pub fn x25519_key_file(secret: &StaticSecret) -> String {
+    format!("# public key: {}\n{}{}\n",
+            format_x25519_public(&PublicKey::from(secret)),
+            X25519_SECRET_PREFIX,
+            to_hex(&secret.to_bytes()))
+}
+
+/// Read the secret key of a key file, skipping comments and blank lines
+pub fn parse_x25519_key_file(text: &str) -> Result<StaticSecret, Error> {
+    match key_line(text) {
+        Some(line) => Ok(StaticSecret::from(try!(parse_key(line, X25519_SECRET_PREFIX)))),
+        None => Err(Error::new("No key found in key file")),
+    }
+}
+
+pub fn format_ed25519_public(key: &VerifyingKey) -> String {
+    format!("{}{}", ED25519_PUBLIC_PREFIX, to_hex(key.as_bytes()))
+}
+
+pub fn parse_ed25519_public(text: &str) -> Result<VerifyingKey, Error> {
+    match VerifyingKey::from_bytes(&try!(parse_key(text.trim(), ED25519_PUBLIC_PREFIX))) {
+        Ok(key) => Ok(key),
+        Err(_) => Err(Error::new("Invalid ed25519 public key")),
+    }
+}
+
+/// Contents of a signing key file, the secret is the 32 bytes seed
+pub fn ed25519_key_file(secret: &SigningKey) -> String {
+    format!("# public key: {}\n{}{}\n",
+            format_ed25519_public(&secret.verifying_key()),
+            ED25519_SECRET_PREFIX,
+            to_hex(&secret.to_bytes()))
+}
+
+pub fn parse_ed25519_key_file(text: &str) -> Result<SigningKey, Error> {
+    match key_line(text) {
+        Some(line) => Ok(SigningKey::from_bytes(&try!(parse_key(line, ED25519_SECRET_PREFIX)))),
+        None => Err(Error::new("No key found in key file")),
+    }
+}
+
+fn random_key() -> Result<[u8; KEY_SIZE], Error> {
+    let mut bytes = [0u8; KEY_SIZE];
+    match getrandom(&mut bytes) {
+        Ok(_) => Ok(bytes),
+        Err(_) => Err(Error::new("Can't get random bytes")),
+    }
+}
+
+fn key_line(text: &str) -> Option<&str> {
+    text.lines().map(|line| line.trim()).find(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn parse_key(text: &str, prefix: &str) -> Result<[u8; KEY_SIZE], Error> {
+    if !text.starts_with(prefix) {
+        return Err(Error::new(&format!("Invalid key, expected {}<hex>", prefix)))
+    }
+    let bytes = try!(from_hex(&text[prefix.len()..]));
+    if bytes.len() != KEY_SIZE {
+        return Err(Error::new("Invalid key length"))
+    }
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, Error> {
+    let digits: Option<Vec<u8>> = text.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect();
+    match digits {
+        Some(ref digits) if digits.len() % 2 == 0 => {
+            Ok(digits.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect())
+        },
+        _ => Err(Error::new("Invalid hex in key")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use x25519_dalek::PublicKey;
+
+    use super::ed25519_key_file;
+    use super::format_ed25519_public;
+    use super::format_x25519_public;
+    use super::generate_ed25519;
+    use super::generate_x25519;
+    use super::parse_ed25519_key_file;
+    use super::parse_ed25519_public;
+    use super::parse_x25519_key_file;
+    use super::parse_x25519_public;
+    use super::x25519_key_file;
+
+    #[test]
+    fn x25519_keys_round_trip() {
+        let secret = generate_x25519().unwrap();
+        let public = PublicKey::from(&secret);
+
+        let text = format_x25519_public(&public);
+        assert_eq!(text.len(), 7 + 64);
+        assert_eq!(parse_x25519_public(&text).unwrap(), public);
+
+        let file = x25519_key_file(&secret);
+        assert!(file.starts_with("# public key: x25519:"));
+        assert_eq!(parse_x25519_key_file(&file).unwrap().to_bytes(), secret.to_bytes());
+
+        assert!(parse_x25519_public(&text[..text.len() - 1]).is_err());
+        assert!(parse_x25519_public(&text.replace("x25519:", "x25519-secret:")).is_err());
+        assert!(parse_x25519_public(&format!("{}0g", &text[..text.len() - 2])).is_err());
+        assert!(parse_x25519_key_file("# nothing here\n\n").is_err());
+    }
+
+    #[test]
+    fn ed25519_keys_round_trip() {
+        let secret = generate_ed25519().unwrap();
+        let public = secret.verifying_key();
+
+        let text = format_ed25519_public(&public);
+        assert_eq!(parse_ed25519_public(&text).unwrap(), public);
+        assert!(parse_ed25519_public(&format_x25519_public(&PublicKey::from(public.to_bytes()))).is_err());
+
+        let file = ed25519_key_file(&secret);
+        assert!(file.starts_with("# public key: ed25519:"));
+        assert_eq!(parse_ed25519_key_file(&file).unwrap().to_bytes(), secret.to_bytes());
+        // key files of one type can't be used as the other
+        assert!(parse_x25519_key_file(&file).is_err());
+        assert!(parse_ed25519_key_file(&x25519_key_file(&generate_x25519().unwrap())).is_err());
+    }
+}
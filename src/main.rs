@@ -2,27 +2,57 @@
 extern crate log;
 extern crate env_logger;
 extern crate libc;
+extern crate argon2;
+extern crate chacha20poly1305;
+extern crate ed25519_dalek;
+extern crate getrandom;
+extern crate miniz_oxide;
+extern crate rand_chacha;
+extern crate hkdf;
+extern crate sha2;
+extern crate x25519_dalek;
 
-#[macro_use]
 mod util;
 mod error;
 mod io;
 mod bitmap;
 mod secret;
+mod payload;
+mod crypto;
+mod ecc;
+mod matrix;
+mod texture;
+mod shuffle;
+mod keys;
 mod args;
 
 use std::env::args as env_args;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::Permissions;
+use std::io::stderr;
+use std::io::stdout;
+use std::io::Cursor;
+use std::io::ErrorKind;
 use std::io::Read;
 use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::process::exit;
 use std::vec::Vec;
 
 use bitmap::Bitmap;
 use secret::BitmapStream;
+use secret::EmbedOptions;
 use args::Args;
+use crypto::Verification;
+use ecc::EccParams;
 use error::Error;
-use util::read_le_u32;
-use util::write_le_u32;
+use payload::FileEntry;
+use payload::Kind;
+use payload::Payload;
+use x25519_dalek::PublicKey;
 
 fn usage() {
     print!(
@@ -30,18 +60,105 @@ r#"
 Steganography tool
 Options:
   -m, --message <message>    - specifies the message to be hidden into image.
-                                 Mandatory in hide command
+                                 Visible to other users, prefer the
+                                 sources below for secrets
+      --message-file <path>  - reads the message from a file
+      --message-env <var>    - reads the message from an environment
+                                 variable
+      --message-stdin        - reads the message from stdin, the
+                                 image must be given with -i
+  -f, --file <file>          - hides a file instead of a message,
+                                 along with its name and mode
+      --password             - encrypts the message, or decrypts it,
+                                 with a password asked on the terminal
+      --password-env <var>   - reads the password from an environment
+                                 variable
+      --password-file <path> - reads the password from the first line
+                                 of a file
+      --recipient <key>      - encrypts the message to a public key
+                                 made by keygen, can be repeated
+      --identity <key_file>  - decrypts a message with a secret key
+                                 file made by keygen
+      --sign-with <key_file> - signs the message with an ed25519 key
+                                 file made by keygen
+      --verify-with <key>    - requires the message to be signed by
+                                 this ed25519 public key
+      --key-type <type>      - x25519 keys to encrypt or ed25519 keys
+                                 to sign. Default: x25519
   -o, --output <destiny>     - sets the destiny output file.
                                  Default: stdout
   -i, --input <input_file>   - sets the input image.
                                  Default: stdin
+      --uncompressed         - writes RLE compressed images back
+                                 uncompressed
+      --depth <bits>         - pixel depth of converted images, 24 or
+                                 32 (keeping alpha). Default: 24
+      --padding              - also hides into unused pixel bits
+                                 (X1R5G5B5 and X8R8G8B8), must be
+                                 given to show as well
+      --key <key>            - spreads the message over the image in an
+                                 order only known with this key, must
+                                 be given to show as well
+      --bits-per-channel <n> - hides into the n (1 to 4) lowest bits of
+                                 channels, or of some of them as in
+                                 r=2,g=2,b=3. Default: 1, must be given
+                                 to show as well
+      --channels <rgba>      - hides only into these channels, some of
+                                 r, g, b and a. Default: all, must be
+                                 given to show as well
+      --translucent-only     - hides only into pixels whose alpha is
+                                 neither 0 nor 255, must be given to
+                                 show as well
+      --adaptive <threshold> - hides only into textured pixels, whose
+                                 channels differ by at least threshold
+                                 (1 to 255) from a neighbour, leaving
+                                 flat areas alone. Must be given to show
+                                 as well
+      --lsb-matching         - adds or subtracts 1 at random to change
+                                 lowest bits instead of setting them,
+                                 for channels hiding one bit. Shown
+                                 like any other message
+      --matrix-embedding     - hides with Hamming codes, changing fewer
+                                 lowest bits the shorter the message is
+                                 for the image. Found out by show
+      --ecc <parity>         - adds Reed-Solomon codes to the message,
+                                 with 2 to 128 parity bytes per block
+                                 of 255 that correct up to half as many
+                                 wrong bytes. Found out by show
+      --ecc-interleave <n>   - spreads the bytes of n blocks among each
+                                 other against damaged areas.
+                                 Default: all blocks
+      --max-length <bytes>   - refuses to show messages longer than
+                                 this, compressed or not. Default: image
+                                 capacity, and 64 MiB once decompressed
+      --legacy               - shows messages hidden by versions
+                                 without payload header
+      --restore-name         - writes a shown file with its original
+                                 name in current directory
 Commands:
   show                       - shows a message hidden in image
   hide                       - hide a message into an image
+  capacity                   - shows how many bytes the image can hide
+  convert                    - writes the image with RGB pixels, which
+                                 hide more than palette ones
+  keygen                     - writes a new secret key file and prints
+                                 its public key
 
 Usage:
-  entrepixels show [-i <input>] [-o <output>]
-  entrepixels hide -m <message> [-i <input>] [-o <output>]
+  entrepixels show [-i <input>] [-o <output> | --restore-name] [--padding] [--key <key>] [--max-length <bytes>]
+                   [--bits-per-channel <n>] [--channels <rgba>] [--translucent-only] [--adaptive <threshold>]
+                   [--password | --password-env <var> | --password-file <path> | --identity <key_file>]
+                   [--verify-with <key>] [--legacy]
+  entrepixels hide (-m <message> | --message-file <path> | --message-env <var> |
+                   --message-stdin | -f <file>) [-i <input>] [-o <output>] [--uncompressed] [--padding]
+                   [--key <key>] [--bits-per-channel <n>] [--channels <rgba>] [--translucent-only]
+                   [--adaptive <threshold> | --lsb-matching] [--matrix-embedding]
+                   [--password | --password-env <var> | --password-file <path> | --recipient <key>...]
+                   [--sign-with <key_file>] [--ecc <parity> [--ecc-interleave <n>]]
+  entrepixels capacity [-i <input>] [-o <output>] [--bits-per-channel <n>] [--channels <rgba>]
+                       [--adaptive <threshold>]
+  entrepixels convert [-i <input>] [-o <output>] [--depth <bits>]
+  entrepixels keygen [-o <key_file>] [--key-type <type>]
 "#
     )
 }
@@ -50,35 +167,115 @@ fn exec_command<'a>(command: String, args: &mut Args) -> Result<(), Error> {
 
     match command.as_str() {
         "show" => {
+            if args.restore_name && args.output_path.is_some() {
+                return Err(Error::new("Use either --output or --restore-name"))
+            }
             let data = try!(read_data(args));
             let bitmap = try!(Bitmap::try_from(data));
-            let mut buf = BitmapStream::from_bitmap(bitmap);
-            let message = try!(read_message(&mut buf));
+            let options = try!(embed_options(args, &bitmap));
+            let mut buf = BitmapStream::from_bitmap(bitmap, &options);
+            let (mut payload, corrected) = try!(read_payload(&mut buf, args.max_length, args.legacy));
+            if let Some(corrected) = corrected {
+                try!(writeln!(stderr(), "Error correction: {} symbol errors corrected", corrected));
+            }
+            try!(crypto::open(&mut payload, args.password.as_ref().map(|password| password.as_bytes()),
+                              args.identity.as_ref()));
+            try!(check_signature(&mut payload, args));
+            try!(payload.decompress(args.max_length));
 
-            try!(writeln!(* args.output, "{}", message));
+            match payload.kind {
+                Kind::Message => {
+                    let message = match String::from_utf8(payload.body) {
+                        Ok(s) => s,
+                        Err(_) => return Err(Error::new("Can't create utf-8 string")),
+                    };
+                    try!(writeln!(try!(open_output(&args.output_path, false)), "{}", message));
+                },
+                Kind::File => {
+                    let entry = try!(FileEntry::parse(&payload.body));
+                    if args.restore_name {
+                        try!(restore_file(&entry));
+                    } else {
+                        try!(try!(open_output(&args.output_path, false)).write_all(&entry.data));
+                    }
+                },
+            }
         },
         "hide" => {
-            if args.message.is_empty() {
-                return Err(Error::new("Empty message"))
+            let mut payload = try!(hide_payload(args));
+            payload.compress();
+            if let Some(ref key) = args.signing_key {
+                crypto::sign(&mut payload, key);
+            }
+            if let Some(ref password) = args.password {
+                try!(crypto::seal_with_password(&mut payload, password.as_bytes()));
+            } else if !args.recipients.is_empty() {
+                try!(crypto::seal_for_recipients(&mut payload, &args.recipients));
             }
 
             let data = try!(read_data(args));
-            let bitmap = try!(Bitmap::try_from(data));
-            let mut buf = BitmapStream::from_bitmap(bitmap);
+            let mut bitmap = try!(Bitmap::try_from(data));
+            bitmap.set_uncompressed_output(args.uncompressed);
+            let options = try!(embed_options(args, &bitmap));
+            let mut buf = BitmapStream::from_bitmap(bitmap, &options);
 
-            try!(write_message(&mut buf, &args.message));
+            match args.ecc {
+                Some(ref ecc) => {
+                    let length = ecc::encoded_len(payload.encoded_len(), ecc);
+                    let capacity = buf.capacity() / 8;
+                    if length > capacity {
+                        return Err(Error::new(&format!("Message too long: {} bytes with error correction, \
+                                                        the image holds up to {} bytes", length, capacity)))
+                    }
+                },
+                None => {
+                    let capacity = message_capacity(&buf);
+                    if payload.body.len() as u64 > capacity {
+                        return Err(Error::new(&format!("Message too long: {} bytes, the image holds up to {} bytes",
+                                                       payload.body.len(), capacity)))
+                    }
+                },
+            }
+            try!(write_payload(&mut buf, &payload, args.ecc.as_ref(), args.matrix_embedding));
 
             let bitmap = buf.into_bitmap();
 
             match Bitmap::try_unwrap_data(bitmap) {
-                Ok(mut data) => {
-                    try!((* args.output).write(&mut data[..]));
+                Ok(data) => {
+                    try!(try!(open_output(&args.output_path, false)).write_all(&data[..]));
                 },
                 Err(_) => {
                     return Err(Error::new("Can't write output"))
                 }
             }
         },
+        "keygen" => {
+            // the secret key file is only for its owner
+            let mut output = try!(open_output(&args.output_path, true));
+            let public = if args.key_type == "ed25519" {
+                let secret = try!(keys::generate_ed25519());
+                try!(write!(output, "{}", keys::ed25519_key_file(&secret)));
+                keys::format_ed25519_public(&secret.verifying_key())
+            } else {
+                let secret = try!(keys::generate_x25519());
+                try!(write!(output, "{}", keys::x25519_key_file(&secret)));
+                keys::format_x25519_public(&PublicKey::from(&secret))
+            };
+            try!(writeln!(stderr(), "Public key: {}", public));
+        },
+        "capacity" => {
+            let data = try!(read_data(args));
+            let mut output = try!(open_output(&args.output_path, false));
+            try!(write_capacity(data, &args.selected_bits_per_channel(), args.adaptive, &mut *output));
+        },
+        "convert" => {
+            let data = try!(read_data(args));
+            let bitmap = try!(try!(Bitmap::try_from(data)).convert(args.depth));
+            match Bitmap::try_unwrap_data(bitmap) {
+                Ok(data) => try!(try!(open_output(&args.output_path, false)).write_all(&data)),
+                Err(_) => return Err(Error::new("Can't write output")),
+            }
+        },
         _ => {
             return Err(Error::new("Invalid command, type `entrelinhas --help` for help"))
         }
@@ -87,6 +284,21 @@ fn exec_command<'a>(command: String, args: &mut Args) -> Result<(), Error> {
     Ok(())
 }
 
+/// Strip the signature of a shown payload and report it on stderr,
+/// failing when it is not valid or missing while a signer was expected.
+/// Unsigned payloads are only reported when a signer was expected
+fn check_signature(payload: &mut Payload, args: &Args) -> Result<(), Error> {
+    match try!(crypto::verify(payload, args.signer.as_ref())) {
+        Verification::Valid(key) => {
+            try!(writeln!(stderr(), "Signature: valid, signed by {}", keys::format_ed25519_public(&key)));
+            Ok(())
+        },
+        Verification::Invalid => Err(Error::new("Signature: invalid")),
+        Verification::Missing if args.signer.is_some() => Err(Error::new("Signature: missing")),
+        Verification::Missing => Ok(()),
+    }
+}
+
 fn read_data(args: &mut Args) -> Result<Vec<u8>, Error> {
     let mut data = Vec::new();
 
@@ -95,28 +307,232 @@ fn read_data(args: &mut Args) -> Result<Vec<u8>, Error> {
     Ok(data)
 }
 
-fn write_message(bit_buf: &mut BitmapStream, message: &String) -> Result<(), Error> {
-    // buffer with 4 bytes to store message size plus the string length in bytes
-    let mut message_len = [0u8; 4];
-    {
-        write_le_u32(&mut message_len[..], 0, message.len() as u32);
+/// Where results go, the file given with -o is only created once
+/// there is something to write to it. A `private` file is only for its
+/// owner from the start, even when it already existed
+fn open_output(path: &Option<String>, private: bool) -> Result<Box<Write>, Error> {
+    match *path {
+        Some(ref path) => {
+            let mode = if private { 0o600 } else { 0o666 };
+            let file = try!(OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path));
+            if private {
+                try!(file.set_permissions(Permissions::from_mode(mode)));
+            }
+            Ok(Box::new(file))
+        },
+        None => Ok(Box::new(stdout())),
+    }
+}
+
+/// How to hide into `bitmap`, as asked in `args`
+fn embed_options(args: &Args, bitmap: &Bitmap) -> Result<EmbedOptions, Error> {
+    let mut options = args.embed_options();
+    if let Some(ref key) = args.key {
+        options.seed = Some(try!(crypto::order_seed(key.as_bytes(), bitmap.width(), bitmap.height())));
+    }
+    if args.lsb_matching {
+        options.matching_seed = Some(try!(shuffle::random_seed()));
+    }
+    if options.translucent_only && bitmap.pixel_format().alpha_mask == 0 {
+        return Err(Error::new("The image has no alpha channel for --translucent-only"))
+    }
+    if options.adaptive.is_some() && !bitmap.palette().is_empty() {
+        return Err(Error::new("--adaptive needs an image without palette"))
+    }
+    if options.adaptive.is_some() && options.matching_seed.is_some() {
+        return Err(Error::new("Use either --lsb-matching or --adaptive"))
+    }
+    Ok(options)
+}
+
+/// Longest message that fits in the image, after the payload header
+fn message_capacity(bit_buf: &BitmapStream) -> u64 {
+    (bit_buf.capacity() / 8).saturating_sub(payload::HEADER_SIZE as u64)
+}
+
+/// Print the capacity of the image for every way of hiding in it,
+/// with `bits_per_channel` low bits of each channel (0 leaves it out),
+/// and in textured pixels for the `adaptive` threshold or a few ones
+fn write_capacity(data: Vec<u8>, bits_per_channel: &[u8; 4], adaptive: Option<u8>, output: &mut Write)
+                  -> Result<(), Error> {
+    let bitmap = try!(Bitmap::try_from(data.clone()));
+    let format = bitmap.pixel_format().clone();
+    try!(writeln!(output, "{}x{}, {} bits per pixel", bitmap.width(), bitmap.height(), format.depth));
+
+    // channels with more than one bit are followed by their number of bits
+    let masks = [format.red_mask, format.green_mask, format.blue_mask, format.alpha_mask];
+    let channels = |bits_per_channel: &[u8; 4]| -> String {
+        if !bitmap.palette().is_empty() {
+            return String::from("palette")
+        }
+        masks.iter().zip(bits_per_channel.iter().zip(&['r', 'g', 'b', 'a']))
+            .filter(|&(mask, (bits, _))| *mask != 0 && *bits != 0)
+            .map(|(_, (bits, name))| if *bits == 1 { name.to_string() } else { format!("{}{}", name, bits) })
+            .collect()
+    };
+
+    let options = EmbedOptions { bits_per_channel: *bits_per_channel, ..EmbedOptions::default() };
+    let mut configurations = vec![(channels(bits_per_channel), options)];
+    if format.padding_mask != 0 {
+        let options = EmbedOptions { padding: true, bits_per_channel: *bits_per_channel, ..EmbedOptions::default() };
+        configurations.push((channels(bits_per_channel) + " --padding", options));
+    }
+    if format.alpha_mask != 0 {
+        // alpha is left alone to tell translucent pixels
+        let mut bits = *bits_per_channel;
+        bits[3] = 0;
+        let options = EmbedOptions { translucent_only: true, bits_per_channel: bits, ..EmbedOptions::default() };
+        configurations.push((channels(&bits) + " --translucent-only", options));
+    }
+    if bitmap.palette().is_empty() {
+        let thresholds = adaptive.map_or(texture::THRESHOLDS.to_vec(), |threshold| vec![threshold]);
+        for threshold in thresholds {
+            let options = EmbedOptions {
+                adaptive: Some(threshold),
+                bits_per_channel: *bits_per_channel,
+                ..EmbedOptions::default()
+            };
+            configurations.push((format!("{} --adaptive {}", channels(bits_per_channel), threshold), options));
+        }
+    }
+
+    try!(writeln!(output, "{:<28} {:>12} {:>12} {:>12}", "channels", "bits", "bytes", "message"));
+    for (name, options) in configurations {
+        let buf = BitmapStream::from_bitmap(try!(Bitmap::try_from(data.clone())), &options);
+        try!(writeln!(output, "{:<28} {:>12} {:>12} {:>12}",
+                      name, buf.capacity(), buf.capacity() / 8, message_capacity(&buf)));
     }
-    try!(bit_buf.write(&message_len[..]));
-    try!(write!(bit_buf, "{}", message));
+    Ok(())
+}
+
+/// The message or file given to hide
+fn hide_payload(args: &Args) -> Result<Payload, Error> {
+    match args.file {
+        Some(_) if !args.message.is_empty() => Err(Error::new("Use either --message or --file")),
+        Some(ref path) => {
+            let entry = try!(read_file_entry(path));
+            Ok(Payload::new(Kind::File, entry.to_bytes()))
+        },
+        None if args.message.is_empty() => Err(Error::new("Empty message")),
+        None => Ok(Payload::new(Kind::Message, args.message.clone().into_bytes())),
+    }
+}
+
+fn read_file_entry(path: &String) -> Result<FileEntry, Error> {
+    let path = Path::new(path);
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => String::from(name),
+        None => return Err(Error::new("Invalid file name")),
+    };
+    let mut file = try!(File::open(path));
+    let mode = try!(file.metadata()).permissions().mode();
+    let mut data = Vec::new();
+    try!(file.read_to_end(&mut data));
+
+    Ok(FileEntry {
+        name: name,
+        mode: mode,
+        data: data,
+    })
+}
+
+/// Write a shown file in the current directory with its original
+/// name and permissions, never replacing an existing file
+fn restore_file(entry: &FileEntry) -> Result<(), Error> {
+    if entry.name.is_empty() || entry.name == "." || entry.name == ".." ||
+        entry.name.contains('/') || entry.name.contains('\0') {
+        return Err(Error::new("Invalid file name"))
+    }
+    let mut file = match OpenOptions::new().write(true).create_new(true).mode(entry.mode & 0o777).open(&entry.name) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == ErrorKind::AlreadyExists => {
+            return Err(Error::new(&format!("File {} already exists", entry.name)))
+        },
+        Err(err) => return Err(Error::from(err)),
+    };
+    try!(file.write_all(&entry.data));
+    Ok(())
+}
+
+fn write_payload(bit_buf: &mut BitmapStream, payload: &Payload, ecc: Option<&EccParams>, matrix: bool)
+                 -> Result<(), Error> {
+    let mut data = Vec::new();
+    try!(payload.write_to(&mut data));
+    if let Some(ecc) = ecc {
+        data = try!(ecc::encode(&data, ecc));
+    }
+    if matrix {
+        data = try!(matrix_embed(bit_buf, &data));
+    }
+    try!(bit_buf.write_all(&data));
     try!(bit_buf.flush());
     Ok(())
 }
 
-fn read_message(bit_buf: &mut BitmapStream) -> Result<String, Error> {
-    let mut message_size = [0u8;4];
-    try!(bit_buf.read(&mut message_size[..]));
-    let message_size = read_le_u32(& message_size, 0);
-    debug!("message size = {}", message_size);
-    let mut data_message = vec![0; message_size as usize];
-    try!(bit_buf.read(&mut data_message[..]));
-    match String::from_utf8(data_message) {
-        Ok(s) => Ok(s),
-        Err(_) => Err(Error::new("Can't create utf-8 string")),
+/// What to write from the first pixel so the syndromes of the carrier
+/// bits spell `data`, behind the header telling the size of groups
+fn matrix_embed(bit_buf: &mut BitmapStream, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let carrier_bits = (bit_buf.capacity() / 8).saturating_sub(matrix::HEADER_SIZE as u64) * 8;
+    let message = matrix::to_bits(data);
+    let k = match matrix::choose_k(message.len() as u64, carrier_bits) {
+        Some(k) => k,
+        None => return Err(Error::new("Message too long for matrix embedding")),
+    };
+
+    let length = matrix::HEADER_SIZE as u64 + matrix::carrier_len(message.len() as u64, k).div_ceil(8);
+    let mut cover = Vec::new();
+    try!((&mut *bit_buf).take(length).read_to_end(&mut cover));
+    bit_buf.rewind();
+
+    let mut carrier = matrix::to_bits(&cover[matrix::HEADER_SIZE..]);
+    let changes = matrix::embed(&mut carrier, &message, k);
+    debug!("matrix embedding k = {}, {} changes for {} bits", k, changes, message.len());
+    let mut output = matrix::header(k);
+    output.extend(matrix::from_bits(&carrier));
+    Ok(output)
+}
+
+/// Read a payload, along with the number of errors corrected when
+/// it was hidden with error correction
+fn read_payload(bit_buf: &mut BitmapStream, max_length: Option<u32>, legacy: bool)
+                -> Result<(Payload, Option<usize>), Error> {
+    let capacity = bit_buf.capacity() / 8;
+    if legacy {
+        return Ok((try!(Payload::read_legacy(bit_buf, capacity, max_length)), None))
+    }
+
+    // matrix embedded messages are read from syndromes past their header
+    let mut start = Vec::new();
+    try!((&mut *bit_buf).take(matrix::HEADER_SIZE as u64).read_to_end(&mut start));
+    match matrix::parse_header(&start) {
+        Some(k) => {
+            let capacity = matrix::capacity(capacity.saturating_sub(matrix::HEADER_SIZE as u64), k);
+            read_frame(&mut matrix::Reader::new(bit_buf, k), capacity, max_length)
+        },
+        None => read_frame(&mut Cursor::new(start).chain(bit_buf), capacity, max_length),
+    }
+}
+
+/// Read a payload from `input` holding up to `capacity` bytes,
+/// through error correction when it was hidden with it
+fn read_frame<R: Read>(input: &mut R, capacity: u64, max_length: Option<u32>)
+                       -> Result<(Payload, Option<usize>), Error> {
+    // error correction frames are told from bare payloads by their header
+    let mut header = Vec::new();
+    try!((&mut *input).take(ecc::HEADER_SIZE as u64).read_to_end(&mut header));
+    match ecc::Frame::parse(&header) {
+        Some(frame) => {
+            if ecc::HEADER_SIZE as u64 + frame.encoded_len() > capacity {
+                return Err(Error::new("No message found: length exceeds image capacity"))
+            }
+            let (data, corrected) = try!(frame.read_data(input));
+            let payload = try!(Payload::read_from(&mut Cursor::new(data), frame.length as u64, max_length));
+            Ok((payload, Some(corrected)))
+        },
+        None => {
+            let payload = try!(Payload::read_from(&mut Cursor::new(header).chain(input), capacity, max_length));
+            Ok((payload, None))
+        },
     }
 }
 
@@ -151,4 +567,291 @@ fn main() {
         },
         _ => {},
     };
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use bitmap::Bitmap;
+    use bitmap::PixelFormat;
+    use secret::BitmapStream;
+    use secret::EmbedOptions;
+    use ecc::EccParams;
+    use payload::Kind;
+    use payload::Payload;
+    use args::Args;
+    use super::embed_options;
+    use super::read_payload;
+    use super::write_capacity;
+    use super::write_payload;
+
+    /// RGB pixels of 8 bits channels
+    pub fn format(depth: u8, alpha_mask: u32) -> PixelFormat {
+        PixelFormat {
+            depth: depth,
+            red_mask: 0xff0000,
+            green_mask: 0xff00,
+            blue_mask: 0xff,
+            alpha_mask: alpha_mask,
+            padding_mask: 0,
+        }
+    }
+
+    /// 16x4 24 bits image, 24 bytes of capacity
+    fn cover(value: u32) -> BitmapStream {
+        BitmapStream::from_bitmap(Bitmap::filled(16, 4, &format(24, 0), &[], value).unwrap(), &EmbedOptions::default())
+    }
+
+    fn reopen(stream: BitmapStream) -> BitmapStream {
+        let data = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+        BitmapStream::from_bitmap(Bitmap::try_from(data).unwrap(), &EmbedOptions::default())
+    }
+
+    #[test]
+    fn read_payload_bounds() {
+        let mut stream = cover(0);
+        assert_eq!(stream.capacity(), 192);
+        let payload = Payload::new(Kind::File, b"12345678".to_vec());
+        write_payload(&mut stream, &payload, None, false).unwrap();
+        let mut stream = reopen(stream);
+        assert_eq!(read_payload(&mut stream, None, false).unwrap(), (payload, None));
+
+        let mut stream = reopen(stream);
+        assert!(read_payload(&mut stream, Some(7), false).is_err());
+
+        // all lsbs set, the length would be 4 GiB
+        assert!(read_payload(&mut cover(0xffffff), None, false).is_err());
+        assert!(read_payload(&mut cover(0xffffff), None, true).is_err());
+    }
+
+    #[test]
+    fn read_legacy_message() {
+        let mut stream = cover(0);
+        stream.write_all(&[3, 0, 0, 0, b'a', b'b', b'c']).unwrap();
+        stream.flush().unwrap();
+        let mut stream = reopen(stream);
+        assert!(read_payload(&mut stream, None, false).is_err());
+        let mut stream = reopen(stream);
+        assert_eq!(read_payload(&mut stream, None, true).unwrap(),
+                   (Payload::new(Kind::Message, b"abc".to_vec()), None));
+    }
+
+    #[test]
+    fn read_payload_with_error_correction() {
+        let bitmap = Bitmap::filled(16, 16, &format(24, 0), &[], 0).unwrap();
+        let mut stream = BitmapStream::from_bitmap(bitmap, &EmbedOptions::default());
+        let payload = Payload::new(Kind::Message, b"hi".to_vec());
+        write_payload(&mut stream, &payload, Some(&EccParams { parity: 4, depth: 0 }), false).unwrap();
+        let mut data = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+
+        // two bits of the first byte of the frame header, and a bit of the payload
+        let pixels = data.len() - 16 * 16 * 3;
+        data[pixels] ^= 1;
+        data[pixels + 1] ^= 1;
+        data[pixels + 200] ^= 1;
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(data).unwrap(), &EmbedOptions::default());
+        assert_eq!(read_payload(&mut stream, None, false).unwrap(), (payload, Some(2)));
+    }
+
+    #[test]
+    fn keyed_order() {
+        let keyed = |seed: u8| EmbedOptions { seed: Some([seed; 32]), ..EmbedOptions::default() };
+        let bitmap = Bitmap::filled(32, 32, &format(24, 0), &[], 0).unwrap();
+        let mut stream = BitmapStream::from_bitmap(bitmap, &keyed(1));
+        let payload = Payload::new(Kind::Message, b"hi".to_vec());
+        write_payload(&mut stream, &payload, None, false).unwrap();
+        let data = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+
+        // bits are spread all over the image, not in the first rows
+        let pixels = data.len() - 32 * 32 * 3;
+        assert!(data[data.len() - 32 * 3..].iter().any(|byte| *byte != 0));
+        assert!(data[pixels + 18 * 8..].iter().any(|byte| *byte != 0));
+
+        let read = |options: &EmbedOptions| {
+            let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(data.clone()).unwrap(), options);
+            read_payload(&mut stream, None, false)
+        };
+        assert_eq!(read(&keyed(1)).unwrap(), (payload, None));
+        assert!(read(&keyed(2)).is_err());
+        assert!(read(&EmbedOptions::default()).is_err());
+    }
+
+    #[test]
+    fn bits_per_channel_with_adjustment() {
+        let options = EmbedOptions { bits_per_channel: [2, 4, 3, 1], ..EmbedOptions::default() };
+        let cover = Bitmap::filled(16, 4, &format(24, 0), &[], 0x7f8081).unwrap();
+        let mut stream = BitmapStream::from_bitmap(cover, &options);
+        assert_eq!(stream.capacity(), 9 * 16 * 4);
+        let payload = Payload::new(Kind::Message, (0..40u32).map(|i| (i * 37) as u8).collect());
+        write_payload(&mut stream, &payload, None, false).unwrap();
+        let data = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(data.clone()).unwrap(), &options);
+        assert_eq!(read_payload(&mut stream, None, false).unwrap(), (payload, None));
+
+        // blue, green, red: no channel is further than 2^(k-1) from its value
+        let pixels = &data[data.len() - 16 * 4 * 3..];
+        for pixel in pixels.chunks(3) {
+            for (value, &(original, bits)) in pixel.iter().zip(&[(0x81, 3), (0x80, 4), (0x7f, 2)]) {
+                let distance = (*value as i32 - original).abs();
+                assert!(distance <= 1 << (bits - 1), "{} from {}", value, original);
+            }
+        }
+        assert!(pixels.chunks(3).any(|pixel| (pixel[1] as i32 - 0x80).abs() > 4));
+    }
+
+    #[test]
+    fn translucent_pixels_only() {
+        // transparent, opaque and translucent columns
+        let bitmap = Bitmap::new(24, 8, &format(32, 0xff000000), &[]).unwrap();
+        for row in 0..8 {
+            for column in 0..24 {
+                let alpha = [0x00, 0xff, 0x80][column as usize % 3];
+                bitmap.pixel(column, row).set_value(alpha << 24 | 0x808080).unwrap();
+            }
+        }
+        let data = Bitmap::try_unwrap_data(bitmap).ok().unwrap();
+        let options = EmbedOptions { translucent_only: true, ..EmbedOptions::default() };
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(data.clone()).unwrap(), &options);
+        assert_eq!(stream.capacity(), 3 * 8 * 8);
+
+        let payload = Payload::new(Kind::Message, b"12345678".to_vec());
+        write_payload(&mut stream, &payload, None, false).unwrap();
+        let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+        let pixels = hidden.len() - 24 * 8 * 4;
+        for (i, (before, after)) in data[pixels..].chunks(4).zip(hidden[pixels..].chunks(4)).enumerate() {
+            assert_eq!(before[3], after[3]);
+            if i % 3 != 2 {
+                assert_eq!(before, after);
+            }
+        }
+
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(hidden).unwrap(), &options);
+        assert_eq!(read_payload(&mut stream, None, false).unwrap(), (payload, None));
+    }
+
+    #[test]
+    fn lsb_matching() {
+        // red and green saturated, blue free to go either way
+        let bitmap = Bitmap::new(16, 8, &format(32, 0), &[]).unwrap();
+        for row in 0..8 {
+            for column in 0..16 {
+                bitmap.pixel(column, row).set_value(0x00ff80).unwrap();
+            }
+        }
+        let data = Bitmap::try_unwrap_data(bitmap).ok().unwrap();
+        let options = EmbedOptions { matching_seed: Some([3; 32]), ..EmbedOptions::default() };
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(data.clone()).unwrap(), &options);
+        let payload = Payload::new(Kind::Message, b"matching".to_vec());
+        write_payload(&mut stream, &payload, None, false).unwrap();
+        let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+
+        let pixels = hidden.len() - 16 * 8 * 4;
+        let channels: Vec<u8> = hidden[pixels..].chunks(4).flat_map(|pixel| pixel[..3].to_vec()).collect();
+        assert!(channels.chunks(3).all(|pixel| [0x7f, 0x80, 0x81].contains(&pixel[0])));
+        assert!(channels.chunks(3).all(|pixel| [0xfe, 0xff].contains(&pixel[1])));
+        assert!(channels.chunks(3).all(|pixel| [0x00, 0x01].contains(&pixel[2])));
+        assert!(channels.contains(&0x7f) && channels.contains(&0x81));
+        assert!(data != hidden);
+
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(hidden).unwrap(), &EmbedOptions::default());
+        assert_eq!(read_payload(&mut stream, None, false).unwrap(), (payload, None));
+    }
+
+    #[test]
+    fn matrix_embedding() {
+        let cover: Vec<u8> = (0..64 * 64 * 3u32).map(|i| (i * 37 % 251) as u8).collect();
+        let bitmap = Bitmap::new(64, 64, &format(24, 0), &[]).unwrap();
+        for (i, pixel) in cover.chunks(3).enumerate() {
+            let value = (pixel[2] as u32) << 16 | (pixel[1] as u32) << 8 | pixel[0] as u32;
+            bitmap.pixel(i as u32 % 64, i as u32 / 64).set_value(value).unwrap();
+        }
+        let data = Bitmap::try_unwrap_data(bitmap).ok().unwrap();
+        let pixels = data.len() - 64 * 64 * 3;
+        let payload = Payload::new(Kind::Message, b"fewer changes".to_vec());
+        let changes = |matrix: bool, ecc: Option<&EccParams>| {
+            let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(data.clone()).unwrap(),
+                                                       &EmbedOptions::default());
+            write_payload(&mut stream, &payload, ecc, matrix).unwrap();
+            let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+            let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(hidden.clone()).unwrap(),
+                                                       &EmbedOptions::default());
+            assert_eq!(read_payload(&mut stream, None, false).unwrap().0, payload);
+            data[pixels..].iter().zip(hidden[pixels..].iter()).filter(|&(a, b)| a != b).count()
+        };
+        // 29 bytes of payload in 12288 bits allows groups of 255 bits,
+        // one change at most for every 8 bits instead of one in 2,
+        // besides the header hidden as usual
+        let matrix = changes(true, None);
+        assert!(matrix < changes(false, None) / 2);
+        assert!(matrix <= 5 * 8 + 29 * 8 / 8);
+        changes(true, Some(&EccParams { parity: 4, depth: 0 }));
+    }
+
+    #[test]
+    fn adaptive_embedding() {
+        // flat left half, busy right half
+        let bitmap = Bitmap::new(32, 16, &format(24, 0), &[]).unwrap();
+        for row in 0..16u32 {
+            for column in 0..32 {
+                let value = if column < 16 { 0x808080 } else { (row * 32 + column).wrapping_mul(0x9e3779) & 0xffffff };
+                bitmap.pixel(column, row).set_value(value).unwrap();
+            }
+        }
+        let data = Bitmap::try_unwrap_data(bitmap).ok().unwrap();
+        let adaptive = |threshold: u8| EmbedOptions {
+            adaptive: Some(threshold),
+            bits_per_channel: [2; 4],
+            ..EmbedOptions::default()
+        };
+        let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(data.clone()).unwrap(), &adaptive(16));
+        // the busy half and the flat column next to it
+        assert!(stream.capacity() >= 6 * 17 * 16 && stream.capacity() < 6 * 20 * 16);
+        let payload = Payload::new(Kind::Message, b"only where it is busy".to_vec());
+        write_payload(&mut stream, &payload, None, false).unwrap();
+        let hidden = Bitmap::try_unwrap_data(stream.into_bitmap()).ok().unwrap();
+
+        let pixels = data.len() - 32 * 16 * 3;
+        for (before, after) in data[pixels..].chunks(32 * 3).zip(hidden[pixels..].chunks(32 * 3)) {
+            assert_eq!(before[..15 * 3], after[..15 * 3]);
+            assert!(before.iter().zip(after.iter()).all(|(a, b)| a & 0xfc == b & 0xfc));
+        }
+        assert!(data != hidden);
+
+        let read = |options: &EmbedOptions| {
+            let mut stream = BitmapStream::from_bitmap(Bitmap::try_from(hidden.clone()).unwrap(), options);
+            read_payload(&mut stream, None, false)
+        };
+        assert_eq!(read(&adaptive(16)).unwrap(), (payload, None));
+        assert!(read(&adaptive(64)).is_err());
+        assert!(read(&EmbedOptions { bits_per_channel: [2; 4], ..EmbedOptions::default() }).is_err());
+    }
+
+    #[test]
+    fn adaptive_embedding_without_matching() {
+        let mut args = Args::from_env_args(vec![String::from("hide")]).unwrap();
+        args.adaptive = Some(16);
+        args.lsb_matching = true;
+        let bitmap = Bitmap::new(4, 4, &format(24, 0), &[]).unwrap();
+        let err = embed_options(&args, &bitmap).err().unwrap();
+        assert_eq!(format!("{}", err), "Err: Use either --lsb-matching or --adaptive");
+    }
+
+    #[test]
+    fn capacity_per_configuration() {
+        let data = Bitmap::try_unwrap_data(Bitmap::new(8, 4, &format(32, 0), &[]).unwrap()).ok().unwrap();
+        let capacity = |bits_per_channel: &[u8; 4]| {
+            let mut output = Vec::new();
+            write_capacity(data.clone(), bits_per_channel, None, &mut output).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            output.lines().skip(2).map(|line| line.split_whitespace().map(String::from).collect())
+                .collect::<Vec<Vec<String>>>()
+        };
+        assert_eq!(capacity(&[1; 4])[..2], [vec!["rgb", "96", "12", "0"], vec!["rgb", "--padding", "352", "44", "28"]]);
+        // nothing is textured in a blank image
+        assert_eq!(capacity(&[1; 4])[2..], [vec!["rgb", "--adaptive", "8", "0", "0", "0"],
+                                            vec!["rgb", "--adaptive", "16", "0", "0", "0"],
+                                            vec!["rgb", "--adaptive", "32", "0", "0", "0"]]);
+        assert_eq!(capacity(&[2, 2, 3, 1])[0], vec!["r2g2b3", "224", "28", "12"]);
+    }
+}
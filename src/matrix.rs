@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::io::Result;
+use std::vec::Vec;
+
+/// First bytes of a matrix embedded message, hidden as usual
+pub const MAGIC: [u8; 4] = [b'E', b'P', b'X', b'M'];
+
+/// magic (4), k (1)
+pub const HEADER_SIZE: usize = 5;
+
+/// Groups of 2^16 - 1 carrier bits are plenty for any cover
+pub const MAX_K: u8 = 16;
+
+/// Carrier bits in a group holding `k` message bits
+pub fn group_size(k: u8) -> u64 {
+    (1u64 << k) - 1
+}
+
+/// Carrier bits needed for `message_bits` with groups of `k` bits
+pub fn carrier_len(message_bits: u64, k: u8) -> u64 {
+    message_bits.div_ceil(k as u64) * group_size(k)
+}
+
+/// Bytes of message that fit in `carrier_bytes` with groups of `k` bits
+pub fn capacity(carrier_bytes: u64, k: u8) -> u64 {
+    carrier_bytes * 8 / group_size(k) * k as u64 / 8
+}
+
+/// Largest `k` fitting `message_bits` in `carrier_bits`: the larger the
+/// groups, the fewer changes per message bit (k / (1 - 2^-k) bits each)
+pub fn choose_k(message_bits: u64, carrier_bits: u64) -> Option<u8> {
+    (1..MAX_K + 1).rev().find(|&k| carrier_len(message_bits, k) <= carrier_bits)
+}
+
+/// Header of a message embedded with groups of `k` bits
+pub fn header(k: u8) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(k);
+    header
+}
+
+/// The `k` of a matrix embedded message, if `header` starts one
+pub fn parse_header(header: &[u8]) -> Option<u8> {
+    if header.len() < HEADER_SIZE || header[..4] != MAGIC {
+        return None
+    }
+    let k = header[4];
+    if (1..MAX_K + 1).contains(&k) { Some(k) } else { None }
+}
+
+/// Xor of the (1-based) positions of the set bits of a group,
+/// which is the product with the parity check matrix of the code
+fn syndrome(group: &[bool]) -> usize {
+    group.iter().enumerate().filter(|&(_, bit)| *bit).fold(0, |syndrome, (i, _)| syndrome ^ (i + 1))
+}
+
+/// Flip at most one bit in each group of `2^k - 1` bits of `carrier`
+/// so that their syndromes spell `message`, `k` bits per group.
+/// Returns how many bits were flipped
+pub fn embed(carrier: &mut [bool], message: &[bool], k: u8) -> usize {
+    let size = group_size(k) as usize;
+    let mut changes = 0;
+    for (group, bits) in carrier.chunks_mut(size).zip(message.chunks(k as usize)) {
+        let wanted = bits.iter().fold(0, |value, bit| value << 1 | *bit as usize) << (k as usize - bits.len());
+        let position = syndrome(group) ^ wanted;
+        if position != 0 {
+            group[position - 1] = !group[position - 1];
+            changes += 1;
+        }
+    }
+    changes
+}
+
+/// Bits of `bytes`, highest first as hidden
+pub fn to_bits(bytes: &[u8]) -> Vec<bool> {
+    bytes.iter().flat_map(|byte| (0..8).rev().map(move |bit| byte >> bit & 1 == 1)).collect()
+}
+
+/// Bytes of `bits`, highest first, the last one completed with zeros
+pub fn from_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8).map(|byte| {
+        byte.iter().enumerate().fold(0u8, |value, (i, bit)| value | (*bit as u8) << (7 - i))
+    }).collect()
+}
+
+/// Message read back from the syndromes of the carrier bits of `input`
+pub struct Reader<R> {
+    input: R,
+    k: u8,
+    /// carrier bits read but not in a group yet
+    carrier: VecDeque<bool>,
+    /// message bits not in a byte yet
+    message: VecDeque<bool>,
+}
+
+impl<R: Read> Reader<R> {
+
+    pub fn new(input: R, k: u8) -> Reader<R> {
+        Reader {
+            input: input,
+            k: k,
+            carrier: VecDeque::new(),
+            message: VecDeque::new(),
+        }
+    }
+
+    /// Next `k` message bits, `None` once the carrier is exhausted
+    fn next_group(&mut self) -> Result<Option<Vec<bool>>> {
+        let size = group_size(self.k) as usize;
+        while self.carrier.len() < size {
+            let mut byte = [0u8];
+            if try!(self.input.read(&mut byte)) == 0 {
+                return Ok(None)
+            }
+            self.carrier.extend(to_bits(&byte));
+        }
+        let group: Vec<bool> = self.carrier.drain(..size).collect();
+        let syndrome = syndrome(&group);
+        Ok(Some((0..self.k).rev().map(|bit| syndrome >> bit & 1 == 1).collect()))
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut read_bytes = 0;
+        while read_bytes != buf.len() {
+            while self.message.len() < 8 {
+                match try!(self.next_group()) {
+                    Some(bits) => self.message.extend(bits),
+                    None => return Ok(read_bytes),
+                }
+            }
+            let byte: Vec<bool> = self.message.drain(..8).collect();
+            buf[read_bytes] = from_bits(&byte)[0];
+            read_bytes += 1;
+        }
+        Ok(read_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::io::Read;
+
+    use super::MAX_K;
+    use super::Reader;
+    use super::carrier_len;
+    use super::choose_k;
+    use super::embed;
+    use super::from_bits;
+    use super::group_size;
+    use super::header;
+    use super::parse_header;
+    use super::to_bits;
+
+    #[test]
+    fn hamming_syndromes() {
+        let message = to_bits(b"matrix embedding");
+        for k in 1..6 {
+            let mut carrier = to_bits(&(0..200u32).map(|i| (i * 73 + 11) as u8).collect::<Vec<u8>>());
+            carrier.truncate(carrier_len(message.len() as u64, k) as usize);
+            let before = carrier.clone();
+            let changes = embed(&mut carrier, &message, k);
+            let groups = carrier.len() / group_size(k) as usize;
+            assert!(changes <= groups);
+            assert_eq!(changes, before.iter().zip(carrier.iter()).filter(|&(a, b)| a != b).count());
+
+            let mut read = Vec::new();
+            Reader::new(Cursor::new(from_bits(&carrier)), k).take(16).read_to_end(&mut read).unwrap();
+            assert_eq!(read, b"matrix embedding");
+        }
+    }
+
+    #[test]
+    fn choose_k_from_ratio() {
+        // 3 bits in 7, 2 bits in 3
+        assert_eq!(choose_k(30, 70), Some(3));
+        assert_eq!(choose_k(30, 69), Some(2));
+        assert_eq!(choose_k(30, 29), None);
+        assert_eq!(choose_k(1, 1 << 20), Some(MAX_K));
+
+        assert_eq!(parse_header(&header(4)), Some(4));
+        assert_eq!(parse_header(&header(0)), None);
+        assert_eq!(parse_header(b"EPXL\x01"), None);
+    }
+}
@@ -0,0 +1,387 @@
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::vec::Vec;
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use miniz_oxide::inflate::TINFLStatus;
+
+use super::error::Error;
+use super::util::read_le_u16;
+use super::util::read_le_u32;
+use super::util::write_le_u16;
+use super::util::write_le_u32;
+
+/// First bytes of every payload, tells it from noise in pixels
+pub const MAGIC: [u8; 4] = [b'E', b'P', b'X', b'L'];
+
+/// Current container version
+pub const VERSION: u8 = 1;
+
+/// magic (4), version (1), kind (1), flags (2), length (4), crc32 (4)
+pub const HEADER_SIZE: usize = 16;
+
+/// Size of the length prefix of the legacy format
+pub const LEGACY_HEADER_SIZE: usize = 4;
+
+/// Body is compressed with raw DEFLATE
+pub const FLAG_COMPRESSED: u16 = 0x0001;
+
+/// Body is encrypted
+pub const FLAG_ENCRYPTED: u16 = 0x0002;
+
+/// Body starts with the signer's key and an Ed25519 signature
+pub const FLAG_SIGNED: u16 = 0x0004;
+
+const KNOWN_FLAGS: u16 = FLAG_COMPRESSED | FLAG_ENCRYPTED | FLAG_SIGNED;
+
+/// Highest DEFLATE level, payloads are small so speed does not matter
+const COMPRESSION_LEVEL: u8 = 10;
+
+/// Decompressed bodies are limited to this unless a maximum length is
+/// given, a few bytes of DEFLATE can expand to gigabytes
+pub const MAX_DECOMPRESSED_LENGTH: u32 = 64 * 1024 * 1024;
+
+/// What the body holds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// UTF-8 text
+    Message,
+    /// A `FileEntry`
+    File,
+}
+
+impl Kind {
+    fn from_u8(kind: u8) -> Result<Kind, Error> {
+        match kind {
+            0 => Ok(Kind::Message),
+            1 => Ok(Kind::File),
+            _ => Err(Error::new("Unsupported payload kind")),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Kind::Message => 0,
+            Kind::File => 1,
+        }
+    }
+}
+
+/// Data hidden in an image, with a versioned header
+///
+/// All header numbers are little endian, the checksum is the CRC32
+/// of the body as stored (after compression and encryption)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Payload {
+    pub kind: Kind,
+    pub flags: u16,
+    pub body: Vec<u8>,
+}
+
+impl Payload {
+
+    pub fn new(kind: Kind, body: Vec<u8>) -> Payload {
+        Payload {
+            kind: kind,
+            flags: 0,
+            body: body,
+        }
+    }
+
+    /// Bytes taken by header and body
+    #[inline(always)]
+    pub fn encoded_len(&self) -> u64 {
+        (HEADER_SIZE + self.body.len()) as u64
+    }
+
+    pub fn write_to<W: Write>(&self, output: &mut W) -> Result<(), Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+        header[5] = self.kind.to_u8();
+        write_le_u16(&mut header, 6, self.flags);
+        write_le_u32(&mut header, 8, self.body.len() as u32);
+        write_le_u32(&mut header, 12, crc32(&self.body));
+
+        try!(output.write_all(&header));
+        try!(output.write_all(&self.body));
+        Ok(())
+    }
+
+    /// Compress the body, unless that does not make it shorter
+    pub fn compress(&mut self) {
+        let compressed = compress_to_vec(&self.body, COMPRESSION_LEVEL);
+        debug!("body length = {}, compressed = {}", self.body.len(), compressed.len());
+        if compressed.len() < self.body.len() {
+            self.body = compressed;
+            self.flags |= FLAG_COMPRESSED;
+        }
+    }
+
+    /// Decompress a compressed body, up to `max_length` bytes
+    pub fn decompress(&mut self, max_length: Option<u32>) -> Result<(), Error> {
+        if self.flags & FLAG_COMPRESSED == 0 {
+            return Ok(())
+        }
+        let limit = max_length.unwrap_or(MAX_DECOMPRESSED_LENGTH) as usize;
+        self.body = match decompress_to_vec_with_limit(&self.body, limit) {
+            Ok(body) => body,
+            Err(ref err) if err.status == TINFLStatus::HasMoreOutput => {
+                return Err(Error::new("Message longer than maximum length"))
+            },
+            Err(_) => return Err(Error::new("Corrupted message: invalid compressed data")),
+        };
+        self.flags &= !FLAG_COMPRESSED;
+        Ok(())
+    }
+
+    /// Read a payload from a stream of `capacity` bytes, the length is
+    /// checked against it and `max_length` before reading the body
+    pub fn read_from<R: Read>(input: &mut R, capacity: u64, max_length: Option<u32>) -> Result<Payload, Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        try!(read_exact(input, &mut header));
+
+        if header[..4] != MAGIC {
+            return Err(Error::new("No message found"))
+        }
+        if header[4] != VERSION {
+            return Err(Error::new("Unsupported payload version"))
+        }
+        let kind = try!(Kind::from_u8(header[5]));
+        let flags = read_le_u16(&header, 6);
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::new("Unsupported payload flags"))
+        }
+        let length = read_le_u32(&header, 8);
+        let checksum = read_le_u32(&header, 12);
+        debug!("payload kind = {:?}, flags = {:x}, length = {}", kind, flags, length);
+
+        try!(check_length(length, capacity, HEADER_SIZE, max_length));
+        let mut body = vec![0; length as usize];
+        try!(read_exact(input, &mut body));
+
+        if crc32(&body) != checksum {
+            return Err(Error::new("Corrupted message: checksum mismatch"))
+        }
+
+        Ok(Payload {
+            kind: kind,
+            flags: flags,
+            body: body,
+        })
+    }
+
+    /// Read a message hidden by older versions: a bare length and the body
+    pub fn read_legacy<R: Read>(input: &mut R, capacity: u64, max_length: Option<u32>) -> Result<Payload, Error> {
+        let mut header = [0u8; LEGACY_HEADER_SIZE];
+        try!(read_exact(input, &mut header));
+        let length = read_le_u32(&header, 0);
+        debug!("legacy message length = {}", length);
+
+        // the length is garbage in images with no message,
+        // so it is checked before anything gets allocated
+        try!(check_length(length, capacity, LEGACY_HEADER_SIZE, max_length));
+        let mut body = vec![0; length as usize];
+        try!(read_exact(input, &mut body));
+
+        Ok(Payload::new(Kind::Message, body))
+    }
+}
+
+/// A file with the metadata needed to restore it
+///
+/// Stored as the name length (u16), the UTF-8 name, the unix mode (u32),
+/// the size (u32) and the file contents
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileEntry {
+    /// file name, without directories
+    pub name: String,
+    pub mode: u32,
+    pub data: Vec<u8>,
+}
+
+impl FileEntry {
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let name = self.name.as_bytes();
+        let mut header = vec![0u8; 2 + name.len() + 8];
+        write_le_u16(&mut header, 0, name.len() as u16);
+        header[2..2 + name.len()].copy_from_slice(name);
+        write_le_u32(&mut header, 2 + name.len(), self.mode);
+        write_le_u32(&mut header, 6 + name.len(), self.data.len() as u32);
+        header.extend_from_slice(&self.data);
+        header
+    }
+
+    pub fn parse(data: &[u8]) -> Result<FileEntry, Error> {
+        if data.len() < 2 {
+            return Err(Error::new("Invalid file entry"))
+        }
+        let name_length = read_le_u16(data, 0) as usize;
+        let header_size = 2 + name_length + 8;
+        if data.len() < header_size {
+            return Err(Error::new("Invalid file entry"))
+        }
+        let name = match String::from_utf8(data[2..2 + name_length].to_vec()) {
+            Ok(name) => name,
+            Err(_) => return Err(Error::new("Invalid file name")),
+        };
+        let mode = read_le_u32(data, 2 + name_length);
+        let size = read_le_u32(data, 6 + name_length) as usize;
+        if data.len() - header_size != size {
+            return Err(Error::new("File size does not match its contents"))
+        }
+
+        Ok(FileEntry {
+            name: name,
+            mode: mode,
+            data: data[header_size..].to_vec(),
+        })
+    }
+}
+
+fn check_length(length: u32, capacity: u64, header_size: usize, max_length: Option<u32>) -> Result<(), Error> {
+    if length as u64 + header_size as u64 > capacity {
+        return Err(Error::new("No message found: length exceeds image capacity"))
+    }
+    if let Some(max_length) = max_length {
+        if length > max_length {
+            return Err(Error::new("Message longer than maximum length"))
+        }
+    }
+    Ok(())
+}
+
+/// Fill `buf` or fail, a short read means there is no message or it was cut
+fn read_exact<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    match input.read_exact(buf) {
+        Ok(_) => Ok(()),
+        Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+            Err(Error::new("No message found or message truncated"))
+        },
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as in zlib and PNG)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::crc32;
+    use super::FileEntry;
+    use super::Kind;
+    use super::Payload;
+    use super::FLAG_COMPRESSED;
+
+    fn encode(payload: &Payload) -> Vec<u8> {
+        let mut data = Vec::new();
+        payload.write_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        let mut payload = Payload::new(Kind::Message, b"hello".to_vec());
+        payload.flags = FLAG_COMPRESSED;
+        let data = encode(&payload);
+        assert_eq!(data.len() as u64, payload.encoded_len());
+        assert_eq!(&data[..8], &[b'E', b'P', b'X', b'L', 1, 0, 1, 0]);
+
+        let capacity = data.len() as u64;
+        assert_eq!(Payload::read_from(&mut Cursor::new(data.clone()), capacity, None).unwrap(), payload);
+        assert!(Payload::read_from(&mut Cursor::new(data.clone()), capacity - 1, None).is_err());
+        assert!(Payload::read_from(&mut Cursor::new(data.clone()), capacity, Some(4)).is_err());
+    }
+
+    #[test]
+    fn invalid_payloads() {
+        let data = encode(&Payload::new(Kind::Message, b"hello".to_vec()));
+        let read = |data: Vec<u8>| Payload::read_from(&mut Cursor::new(data), 100, None);
+
+        // noise, future version, unknown flags
+        for &(position, value) in &[(0, b'e'), (4, 2), (5, 9), (7, 0x80)] {
+            let mut data = data.clone();
+            data[position] = value;
+            assert!(read(data).is_err());
+        }
+
+        // corrupted body
+        let mut corrupted = data.clone();
+        corrupted[17] ^= 1;
+        assert!(read(corrupted).is_err());
+
+        // truncated body
+        assert!(read(data[..data.len() - 1].to_vec()).is_err());
+    }
+
+    #[test]
+    fn compression() {
+        let text = b"to be or not to be, that is the question. ".repeat(20);
+        let mut payload = Payload::new(Kind::Message, text.clone());
+        payload.compress();
+        assert_eq!(payload.flags, FLAG_COMPRESSED);
+        assert!(payload.body.len() < text.len() / 4);
+
+        let mut limited = payload.clone();
+        assert!(limited.decompress(Some(text.len() as u32 - 1)).is_err());
+        payload.decompress(Some(text.len() as u32)).unwrap();
+        assert_eq!(payload, Payload::new(Kind::Message, text));
+
+        // left alone when it would not shrink
+        let mut short = Payload::new(Kind::Message, b"hi".to_vec());
+        short.compress();
+        assert_eq!(short, Payload::new(Kind::Message, b"hi".to_vec()));
+        short.decompress(None).unwrap();
+        assert_eq!(short.body, b"hi".to_vec());
+
+        let mut corrupted = Payload::new(Kind::Message, vec![0xff; 8]);
+        corrupted.flags = FLAG_COMPRESSED;
+        assert!(corrupted.decompress(None).is_err());
+    }
+
+    #[test]
+    fn legacy_payload() {
+        let data = vec![3, 0, 0, 0, b'a', b'b', b'c'];
+        let payload = Payload::read_legacy(&mut Cursor::new(data.clone()), 7, None).unwrap();
+        assert_eq!(payload.body, b"abc".to_vec());
+        assert!(Payload::read_legacy(&mut Cursor::new(data), 6, None).is_err());
+    }
+
+    #[test]
+    fn file_entry_round_trip() {
+        let entry = FileEntry {
+            name: String::from("key.pem"),
+            mode: 0o100600,
+            data: vec![0, 1, 2, 0xff],
+        };
+        let data = entry.to_bytes();
+        assert_eq!(data.len(), 2 + 7 + 8 + 4);
+        assert_eq!(FileEntry::parse(&data).unwrap(), entry);
+
+        assert!(FileEntry::parse(&data[..data.len() - 1]).is_err());
+        assert!(FileEntry::parse(&data[..10]).is_err());
+        let mut invalid_name = data.clone();
+        invalid_name[2] = 0xff;
+        assert!(FileEntry::parse(&invalid_name).is_err());
+    }
+}
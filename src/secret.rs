@@ -1,76 +1,248 @@
 use std::io::Read;
 use std::io::Write;
 use std::io::Result;
+use std::cmp::Ordering;
 
 use super::bitmap::Bitmap;
+use super::bitmap::LuminanceOrder;
 use super::bitmap::Pixel;
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::RngCore;
+use rand_chacha::rand_core::SeedableRng;
 
-macro_rules! mask_lsb {
-    ($( $x:expr ),*) => {
-        [$(match $x.trailing_zeros() {
-            x if x >= 32 => 0u32,
-            x @ _ => 1u32 << x,
-        }),*]
+use super::shuffle::Shuffle;
+use super::shuffle::SEED_SIZE;
+use super::texture::TextureMap;
+
+/// Most bits of a channel that may carry secret bits
+pub const MAX_BITS_PER_CHANNEL: u8 = 4;
+
+/// Which parts of pixels carry secret bits,
+/// hiding and showing must be done with the same options
+pub struct EmbedOptions {
+    /// also use pixel bits out of any channel
+    /// (the X bits of X1R5G5B5 and X8R8G8B8)
+    pub padding: bool,
+    /// visit pixels, and bits in them, in an order drawn from this seed
+    /// instead of one after the other
+    pub seed: Option<[u8; SEED_SIZE]>,
+    /// low bits of each channel (rgba) carrying secret bits,
+    /// limited to the size of the channel, 0 leaves the channel out
+    pub bits_per_channel: [u8; 4],
+    /// only use pixels whose alpha is neither 0 nor the highest, alpha
+    /// itself is left alone so the pixels can be told when showing
+    pub translucent_only: bool,
+    /// LSB matching: change channels with a single secret bit by
+    /// adding or subtracting 1, at random from this seed, instead of
+    /// replacing their lsb. Showing reads lsbs all the same
+    pub matching_seed: Option<[u8; SEED_SIZE]>,
+    /// only use pixels with a texture of at least this threshold (see
+    /// `TextureMap`), which leaves out flat areas. Bits above the secret
+    /// ones are kept as they are, without adjustment. Not to be combined
+    /// with `matching_seed`, whose carries would change the texture
+    pub adaptive: Option<u8>,
+}
+
+impl Default for EmbedOptions {
+    fn default() -> EmbedOptions {
+        EmbedOptions {
+            padding: false,
+            seed: None,
+            bits_per_channel: [1; 4],
+            translucent_only: false,
+            matching_seed: None,
+            adaptive: None,
+        }
     }
 }
 
 pub struct BitmapStream {
-    /// masks of secret message bits
-    /// max of 4 (rgba)
-    masks: [u32; 4],
+    /// masks of secret message bits, the low bits of each
+    /// channel (rgba) followed by padding bits
+    masks: Vec<u32>,
+    /// mask of the whole channel of each bit of `masks`, 0 for padding
+    channel_masks: Vec<u32>,
+    /// all the secret bits in the channel of each bit of `masks`
+    secret_masks: Vec<u32>,
     /// max number of bits saved in a pixel
     bits_per_pixel: u8,
+    /// alpha mask when only translucent pixels are used, 0 otherwise
+    alpha_mask: u32,
+    /// textured pixels in adaptive mode
+    texture: Option<TextureMap>,
+    /// number of pixels carrying bits
+    pixel_count: u64,
+    /// palette sorted by luminance, for indexed images
+    /// the secret bit is the parity of pixel color in this order
+    palette_order: Option<LuminanceOrder>,
     /// bitmap
     bitmap: Bitmap,
+    /// key of the order of pixels, to start it again
+    seed: Option<[u8; SEED_SIZE]>,
+    /// keyed order of pixels, in storage order when `None`
+    shuffle: Option<Shuffle>,
+    /// picks +1 or -1 for LSB matching
+    matching: Option<ChaCha20Rng>,
+    /// order of `masks` in current pixel
+    bit_order: Vec<usize>,
     /// current reading/writing pixel
     cur_pixel: Option<Pixel>,
+    /// value of current pixel before writing to it
+    original: Option<u32>,
     /// bit position in pixel
     bit_pos: u8,
 }
 
 impl BitmapStream {
 
-    pub fn from_bitmap(bitmap: Bitmap) -> BitmapStream {
-        // map the lsb of a mask (I do't know how to name this)
-        // E.G.:
-        // 0xff0000 maps in 0x010000
-
-        let mut masks = {
+    pub fn from_bitmap(mut bitmap: Bitmap, options: &EmbedOptions) -> BitmapStream {
+        let (mut channels, padding_mask, alpha_mask) = {
             let pixel_format = bitmap.pixel_format();
-            mask_lsb![
-                pixel_format.red_mask,
-                pixel_format.green_mask,
-                pixel_format.blue_mask,
-                pixel_format.alpha_mask
-            ]
+            let (alpha_bits, alpha_mask) = if options.translucent_only {
+                (0, pixel_format.alpha_mask)
+            } else {
+                (options.bits_per_channel[3], 0)
+            };
+            ([
+                (pixel_format.red_mask, options.bits_per_channel[0]),
+                (pixel_format.green_mask, options.bits_per_channel[1]),
+                (pixel_format.blue_mask, options.bits_per_channel[2]),
+                (pixel_format.alpha_mask, alpha_bits),
+            ], pixel_format.padding_mask, alpha_mask)
         };
 
-        // sort in reverse order to keep big endian order
-        masks.sort_by(|a,b| a.cmp(b).reverse());
+        // sort from lowest to highest byte to keep storage order,
+        // unused (zeroed) masks go to the end
+        channels.sort_by(|&(a, _), &(b, _)| match (a, b) {
+            (0, 0) => Ordering::Equal,
+            (0, _) => Ordering::Greater,
+            (_, 0) => Ordering::Less,
+            (a, b) => a.cmp(&b),
+        });
 
-        // count masks different of zero
-        let bits_per_pixel = masks.iter().fold(0, |sum, mask| sum + if mask != &0 {1} else {0});
+        let mut masks = Vec::new();
+        let mut channel_masks = Vec::new();
+        let mut secret_masks = Vec::new();
+        let mut texture_channels = Vec::new();
+        for &(channel, bits) in channels.iter().filter(|&&(mask, _)| mask != 0) {
+            let low = low_bits(channel, bits);
+            let secret = low.iter().fold(0, |secret, bit| secret | bit);
+            texture_channels.push((channel, secret));
+            channel_masks.extend(low.iter().map(|_| channel));
+            secret_masks.extend(low.iter().map(|_| secret));
+            masks.extend(low);
+        }
+
+        // padding bits are not shown, so all of them are used
+        if options.padding {
+            let padding = low_bits(padding_mask, 32);
+            channel_masks.extend(padding.iter().map(|_| 0));
+            secret_masks.extend(padding.iter().map(|_| 0));
+            masks.extend(padding);
+        }
+
+        let palette_order = if bitmap.palette().is_empty() {
+            None
+        } else {
+            Some(LuminanceOrder::from_colors(bitmap.palette().colors()))
+        };
+
+        // indexed pixels carry a single bit
+        let bits_per_pixel = match palette_order {
+            Some(_) if bitmap.palette().len() > 1 => 1,
+            Some(_) => 0,
+            None => masks.len() as u8,
+        };
+
+        // indexes change to another color of the palette, texture can't tell
+        let texture = match (options.adaptive, &palette_order) {
+            (Some(threshold), &None) => Some(TextureMap::from_bitmap(&bitmap, &texture_channels, threshold)),
+            _ => None,
+        };
+
+        let pixel_count = if alpha_mask != 0 || texture.is_some() {
+            bitmap.pixels_from(&None).filter(|pixel| carries_bits(&mut pixel.clone(), alpha_mask, &texture)).count() as u64
+        } else {
+            bitmap.width() as u64 * bitmap.height() as u64
+        };
+
+        let shuffle = options.seed.map(|seed| {
+            Shuffle::new(&seed, bitmap.width() as u64 * bitmap.height() as u64)
+        });
 
         BitmapStream {
             bitmap: bitmap,
             masks: masks,
+            channel_masks: channel_masks,
+            secret_masks: secret_masks,
             bits_per_pixel: bits_per_pixel,
+            alpha_mask: alpha_mask,
+            texture: texture,
+            pixel_count: pixel_count,
+            palette_order: palette_order,
+            seed: options.seed,
+            shuffle: shuffle,
+            matching: options.matching_seed.map(ChaCha20Rng::from_seed),
+            bit_order: (0..bits_per_pixel as usize).collect(),
             cur_pixel: None,
+            original: None,
             bit_pos: 0,
         }
     }
 
+    /// Number of bits that can be hidden in the whole image
+    pub fn capacity(&self) -> u64 {
+        self.bits_per_pixel as u64 * self.pixel_count
+    }
+
+    /// Go back to the first pixel, to write over what was read
+    pub fn rewind(&mut self) {
+        let size = self.bitmap.width() as u64 * self.bitmap.height() as u64;
+        self.shuffle = self.seed.map(|seed| Shuffle::new(&seed, size));
+        self.bit_order = (0..self.bits_per_pixel as usize).collect();
+        self.cur_pixel = None;
+        self.original = None;
+        self.bit_pos = 0;
+    }
+
     pub fn into_bitmap(self) -> Bitmap {
         self.bitmap
     }
 
+    /// Move to the next pixel, in order or as the key tells
+    fn next_pixel(&mut self) -> Option<Pixel> {
+        self.bit_pos = 0;
+        self.original = None;
+        let alpha_mask = self.alpha_mask;
+        let texture = &self.texture;
+        match self.shuffle {
+            Some(ref mut shuffle) => {
+                let width = self.bitmap.width() as u64;
+                loop {
+                    let index = match shuffle.next() {
+                        Some(index) => index,
+                        None => return None,
+                    };
+                    let mut pixel = self.bitmap.pixel((index % width) as u32, (index / width) as u32);
+                    if carries_bits(&mut pixel, alpha_mask, texture) {
+                        self.bit_order = shuffle.permutation(self.bits_per_pixel as usize);
+                        return Some(pixel)
+                    }
+                }
+            },
+            None => self.bitmap.pixels_from(&self.cur_pixel)
+                .find(|pixel| carries_bits(&mut pixel.clone(), alpha_mask, texture)),
+        }
+    }
+
     fn read_bit(&mut self, pixel: &mut Pixel) -> Option<bool> {
         if self.bit_pos >= self.bits_per_pixel { return None }
-        let mask = self.masks[self.bit_pos as usize];
+        let bit_pos = self.bit_pos as usize;
         self.bit_pos += 1;
-        match pixel.value() {
-            Ok(value) => Some(value & mask != 0),
-            Err(_) => None,
+        match (pixel.value(), &self.palette_order) {
+            (Ok(value), &Some(ref order)) => order.parity(value),
+            (Ok(value), &None) => Some(value & self.masks[self.bit_order[bit_pos]] != 0),
+            (Err(_), _) => None,
         }
     }
 
@@ -78,12 +250,30 @@ impl BitmapStream {
         if self.bit_pos >= self.bits_per_pixel {
             return Err(::std::io::Error::new(::std::io::ErrorKind::Other, "End of pixel"))
         }
-        let mask = self.masks[self.bit_pos as usize];
-        debug!("mask = {:x}", mask);
-        let data = if bit {
-            try!(pixel.value()) | mask
-        } else {
-            try!(pixel.value()) & !mask
+        let data = match self.palette_order {
+            Some(ref order) => match order.with_parity(try!(pixel.value()), bit) {
+                Some(index) => index,
+                None => return Err(::std::io::Error::new(::std::io::ErrorKind::Other, "Pixel out of palette")),
+            },
+            None => {
+                let index = self.bit_order[self.bit_pos as usize];
+                let mask = self.masks[index];
+                debug!("mask = {:x}", mask);
+                let value = try!(pixel.value());
+                let original = *self.original.get_or_insert(value);
+                let (channel, secret) = (self.channel_masks[index], self.secret_masks[index]);
+                let adaptive = self.texture.is_some();
+                match self.matching {
+                    Some(ref mut rng) if channel != 0 && secret == mask && (value & mask != 0) != bit => {
+                        match_lsb(value, channel, rng)
+                    },
+                    _ => {
+                        let value = if bit { value | mask } else { value & !mask };
+                        // texture is measured on the bits above the secret ones
+                        if adaptive { value } else { adjust(value, original, channel, secret) }
+                    },
+                }
+            },
         };
 
         self.bit_pos += 1;
@@ -93,6 +283,73 @@ impl BitmapStream {
     }
 }
 
+/// Whether `pixel` is used, with `alpha_mask` only translucent ones are
+/// and with `texture` only textured ones
+fn carries_bits(pixel: &mut Pixel, alpha_mask: u32, texture: &Option<TextureMap>) -> bool {
+    if let Some(ref texture) = *texture {
+        if !texture.is_textured(pixel) {
+            return false
+        }
+    }
+    if alpha_mask == 0 {
+        return true
+    }
+    match pixel.value() {
+        Ok(value) => value & alpha_mask != 0 && value & alpha_mask != alpha_mask,
+        // let reading or writing report it
+        Err(_) => true,
+    }
+}
+
+/// The `bits` lowest set bits of `mask`, one mask for each
+fn low_bits(mask: u32, bits: u8) -> Vec<u32> {
+    (0..32).map(|bit| 1u32 << bit).filter(|bit| mask & bit != 0).take(bits as usize).collect()
+}
+
+/// Optimal pixel adjustment: once the `secret` bits of a channel are
+/// set, moving the bits above them by one step (2^k for k secret bits)
+/// may bring the channel closer to its `original` value
+fn adjust(value: u32, original: u32, channel: u32, secret: u32) -> u32 {
+    if channel == 0 {
+        return value
+    }
+    let shift = channel.trailing_zeros();
+    let max = channel >> shift;
+    let step = (secret >> shift) + 1;
+    if step > max {
+        return value
+    }
+
+    let target = (original & channel) >> shift;
+    let distance = |x: u32| if x > target { x - target } else { target - x };
+    // from the original upper bits, whatever was done to them before
+    let current = ((original & channel & !secret) | (value & secret)) >> shift;
+    let mut best = current;
+    if current >= step && distance(current - step) < distance(best) {
+        best = current - step;
+    }
+    if current + step <= max && distance(current + step) < distance(best) {
+        best = current + step;
+    }
+    value & !channel | best << shift
+}
+
+/// Flip the lsb of `channel` by adding or subtracting 1, whichever
+/// stays in range or at random when both do
+fn match_lsb(value: u32, channel: u32, rng: &mut ChaCha20Rng) -> u32 {
+    let shift = channel.trailing_zeros();
+    let max = channel >> shift;
+    let current = (value & channel) >> shift;
+    let changed = if current == 0 {
+        1
+    } else if current == max || rng.next_u32() & 1 == 0 {
+        current - 1
+    } else {
+        current + 1
+    };
+    value & !channel | changed << shift
+}
+
 impl Read for BitmapStream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let mut byte = 0u8;
@@ -125,8 +382,7 @@ impl Read for BitmapStream {
                             Some(pixel)
                         },
                         None => {
-                            self.bit_pos = 0;
-                            match self.bitmap.pixels_from(&self.cur_pixel).next() {
+                            match self.next_pixel() {
                                 Some(pixel) => Some(pixel),
                                 None => return Ok(read_bytes),
                             }
@@ -134,8 +390,7 @@ impl Read for BitmapStream {
                     }
                 },
                 None => {
-                    self.bit_pos = 0;
-                    match self.bitmap.pixels_from(&self.cur_pixel).next() {
+                    match self.next_pixel() {
                         Some(pixel) => Some(pixel),
                         None => return Ok(read_bytes),
                     }
@@ -186,8 +441,7 @@ impl Write for BitmapStream {
                             Some(pixel)
                         },
                         Err(_) => {
-                            self.bit_pos = 0;
-                            match self.bitmap.pixels_from(&self.cur_pixel).next() {
+                            match self.next_pixel() {
                                 Some(pixel) => Some(pixel),
                                 None => return Ok(write_bytes),
                             }
@@ -195,8 +449,7 @@ impl Write for BitmapStream {
                     }
                 },
                 None => {
-                    self.bit_pos = 0;
-                    match self.bitmap.pixels_from(&self.cur_pixel).next() {
+                    match self.next_pixel() {
                         Some(pixel) => Some(pixel),
                         None => return Ok(write_bytes),
                     }
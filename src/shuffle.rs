@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::iter::Iterator;
+use std::vec::Vec;
+
+use getrandom::getrandom;
+use rand_chacha::ChaCha20Rng;
+use rand_chacha::rand_core::RngCore;
+use rand_chacha::rand_core::SeedableRng;
+
+use super::error::Error;
+
+pub const SEED_SIZE: usize = 32;
+
+/// Seed for choices that need not be repeated when showing
+pub fn random_seed() -> Result<[u8; SEED_SIZE], Error> {
+    let mut seed = [0u8; SEED_SIZE];
+    match getrandom(&mut seed) {
+        Ok(_) => Ok(seed),
+        Err(_) => Err(Error::new("Can't get random bytes")),
+    }
+}
+
+/// Random order of `0..size` drawn from a seeded ChaCha20 stream
+///
+/// A Fisher-Yates shuffle done as it goes, only the positions swapped so
+/// far are kept, so a short message in a large image costs what it uses
+pub struct Shuffle {
+    rng: ChaCha20Rng,
+    size: u64,
+    position: u64,
+    swaps: HashMap<u64, u64>,
+}
+
+impl Shuffle {
+
+    pub fn new(seed: &[u8; SEED_SIZE], size: u64) -> Shuffle {
+        Shuffle {
+            rng: ChaCha20Rng::from_seed(*seed),
+            size: size,
+            position: 0,
+            swaps: HashMap::new(),
+        }
+    }
+
+    /// Random order of `0..count`, all of it at once
+    pub fn permutation(&mut self, count: usize) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..count).collect();
+        for i in (1..count).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            order.swap(i, j);
+        }
+        order
+    }
+
+    /// Uniform number lower than `bound`
+    fn below(&mut self, bound: u64) -> u64 {
+        // values in the last incomplete range of `bound` would favor small numbers
+        let threshold = bound.wrapping_neg() % bound;
+        loop {
+            let x = self.rng.next_u64();
+            if x >= threshold {
+                return x % bound
+            }
+        }
+    }
+}
+
+impl Iterator for Shuffle {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        if self.position >= self.size {
+            return None
+        }
+        let position = self.position;
+        let other = position + self.below(self.size - position);
+        let value = *self.swaps.get(&other).unwrap_or(&other);
+        let current = self.swaps.remove(&position).unwrap_or(position);
+        if other != position {
+            self.swaps.insert(other, current);
+        }
+        self.position += 1;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shuffle;
+
+    #[test]
+    fn shuffles_are_permutations() {
+        let mut values: Vec<u64> = Shuffle::new(&[7; 32], 1000).collect();
+        assert_eq!(values.len(), 1000);
+        assert!(values.iter().take(20).zip(0..).any(|(value, i)| *value != i));
+        values.sort();
+        assert_eq!(values, (0..1000).collect::<Vec<u64>>());
+
+        // same seed same order, other seed other order
+        let first: Vec<u64> = Shuffle::new(&[7; 32], 1000).take(50).collect();
+        assert_eq!(first, Shuffle::new(&[7; 32], 1000).take(50).collect::<Vec<u64>>());
+        assert!(first != Shuffle::new(&[8; 32], 1000).take(50).collect::<Vec<u64>>());
+
+        let mut order = Shuffle::new(&[7; 32], 0).permutation(4);
+        order.sort();
+        assert_eq!(order, vec![0, 1, 2, 3]);
+        assert_eq!(Shuffle::new(&[7; 32], 0).next(), None);
+    }
+}
@@ -0,0 +1,96 @@
+use std::cmp::max;
+use std::cmp::min;
+use std::vec::Vec;
+
+use super::bitmap::Bitmap;
+use super::bitmap::Pixel;
+use super::bitmap::channel_level;
+
+/// Thresholds shown by capacity when none is given
+pub const THRESHOLDS: [u8; 3] = [8, 16, 32];
+
+/// Which pixels are busy enough to hide bits in
+///
+/// The texture of a pixel is the largest difference of one of its
+/// channels with the same channel of a neighbour (left, right, above or
+/// below), scaled to 0 to 255. Only the bits above the secret ones are
+/// compared, so hiding does not change which pixels are used
+pub struct TextureMap {
+    width: u32,
+    textured: Vec<bool>,
+}
+
+impl TextureMap {
+
+    /// Pixels of `bitmap` with a texture of at least `threshold`,
+    /// `channels` are the masks of each channel and of its secret bits
+    pub fn from_bitmap(bitmap: &Bitmap, channels: &[(u32, u32)], threshold: u8) -> TextureMap {
+        let (width, height) = (bitmap.width() as usize, bitmap.height() as usize);
+        let mut values = Vec::with_capacity(width * height);
+        for row in 0..height {
+            for column in 0..width {
+                // unreadable pixels are reported when hiding or showing
+                values.push(bitmap.pixel(column as u32, row as u32).value().unwrap_or(0));
+            }
+        }
+
+        let difference = |a: u32, b: u32| {
+            channels.iter().map(|&(channel, secret)| {
+                let (a, b) = (channel_level(a, channel & !secret), channel_level(b, channel & !secret));
+                max(a, b) - min(a, b)
+            }).max().unwrap_or(0)
+        };
+
+        // each pixel is compared with the next one in its row and above it
+        let mut textures = vec![0u8; width * height];
+        for row in 0..height {
+            for column in 0..width {
+                let index = row * width + column;
+                if column + 1 < width {
+                    let texture = difference(values[index], values[index + 1]);
+                    textures[index] = max(textures[index], texture);
+                    textures[index + 1] = max(textures[index + 1], texture);
+                }
+                if row + 1 < height {
+                    let texture = difference(values[index], values[index + width]);
+                    textures[index] = max(textures[index], texture);
+                    textures[index + width] = max(textures[index + width], texture);
+                }
+            }
+        }
+
+        TextureMap {
+            width: width as u32,
+            textured: textures.iter().map(|texture| *texture >= threshold).collect(),
+        }
+    }
+
+    pub fn is_textured(&self, pixel: &Pixel) -> bool {
+        self.textured[pixel.row() as usize * self.width as usize + pixel.column() as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TextureMap;
+    use super::super::bitmap::Bitmap;
+    use super::super::tests::format;
+
+    #[test]
+    fn textured_pixels() {
+        // flat but for a bright pixel and noise in the lowest bits
+        let mut bitmap = Bitmap::filled(4, 3, &format(24, 0), &[], 0x404040).unwrap();
+        bitmap.pixel(0, 2).set_value(0x414141).unwrap();
+        bitmap.pixel(2, 1).set_value(0x406040).unwrap();
+        let channels = [(0xff0000, 0x10000), (0xff00, 0x100), (0xff, 0x1)];
+        let texture = TextureMap::from_bitmap(&bitmap, &channels, 16);
+        let textured: Vec<(u32, u32)> = bitmap.pixels_from(&None).filter(|pixel| texture.is_textured(pixel))
+            .map(|pixel| (pixel.column(), pixel.row())).collect();
+        assert_eq!(textured, vec![(2, 0), (1, 1), (2, 1), (3, 1), (2, 2)]);
+
+        // without secret bits the lowest ones count as well
+        let texture = TextureMap::from_bitmap(&bitmap, &[(0xff, 0)], 1);
+        assert!(texture.is_textured(&bitmap.pixel(0, 2)));
+        assert!(!texture.is_textured(&bitmap.pixel(3, 0)));
+    }
+}